@@ -10,6 +10,9 @@ pub struct AllPass {
     y_buffer: Box<CircularBuffer::<41100, f64>>,
     delay: usize,
     gain: f64,
+    // gain and 1.0 for a true allpass, freeverb uses 1.0 and 1.0 + gain
+    feedforward: f64,
+    delayed_gain: f64,
 }
 
 impl AllPass {
@@ -35,13 +38,26 @@ impl AllPass {
             y_buffer: buffer2,
             delay,
             gain: gain,
+            feedforward: gain,
+            delayed_gain: 1.0,
         }
     }
 
+    // the allpass used by freeverb, v(n) = x(n) + g * v(n - d), y(n) = v(n - d) - x(n).
+    // it isn't a true allpass, but it is needed to match presets made with freeverb
+    pub fn new_freeverb(delay: usize, gain: f64) -> Self {
+        let mut allpass = Self::new(delay, gain);
+        allpass.feedforward = 1.0;
+        allpass.delayed_gain = 1.0 + allpass.gain;
+        allpass
+    }
+
+    // y(n) = -c * x(n) + b * x(n - d) + g * y(n - d)
+    // with c = g and b = 1 this is the schroeder allpass
     pub fn process_sample(&mut self, x: f64) -> f64 {
         let y = 
-        -self.gain * x 
-        + self.x_buffer.get(self.delay).unwrap() 
+        -self.feedforward * x 
+        + self.delayed_gain * self.x_buffer.get(self.delay).unwrap() 
         + self.gain * self.y_buffer.get(self.delay).unwrap();
 
         self.x_buffer.push_front(x);
@@ -49,6 +65,36 @@ impl AllPass {
 
         y
     }
+
+    // same as process_sample, but the delay is read with linear interpolation,
+    // so it can be modulated without clicks
+    pub fn process_sample_fractional(&mut self, x: f64, delay: f64) -> f64 {
+        let delay = delay.clamp(0.0, 41100.0 - 2.0);
+        let index = delay.floor() as usize;
+        let frac = delay - index as f64;
+
+        let x_delayed = self.x_buffer.get(index).unwrap() * (1.0 - frac) + self.x_buffer.get(index + 1).unwrap() * frac;
+        let y_delayed = self.y_buffer.get(index).unwrap() * (1.0 - frac) + self.y_buffer.get(index + 1).unwrap() * frac;
+
+        let y = 
+        -self.feedforward * x 
+        + self.delayed_gain * x_delayed 
+        + self.gain * y_delayed;
+
+        self.x_buffer.push_front(x);
+        self.y_buffer.push_front(y);
+
+        y
+    }
+
+    pub fn delay(&self) -> usize {
+        self.delay
+    }
+
+    // reads the input history, tap(0) is the last sample that went in
+    pub fn tap(&self, index: usize) -> f64 {
+        *self.x_buffer.get(index).unwrap()
+    }
 }
 
 impl AudioNode for AllPass {
@@ -68,15 +114,7 @@ impl AudioNode for AllPass {
             input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
         ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
         let x = input[0] as f64;
-        
-        let y = 
-        -self.gain * x 
-        + self.x_buffer.get(self.delay).unwrap() 
-        + self.gain * self.y_buffer.get(self.delay).unwrap();
-
-        self.x_buffer.push_front(x);
-        self.y_buffer.push_front(y);
-
+        let y = self.process_sample(x);
         [y].into()
     }
 }
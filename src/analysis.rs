@@ -0,0 +1,150 @@
+use fundsp::audiounit::AudioUnit64;
+
+use crate::filter::{BiquadFilter, band_pass_coefficients};
+
+pub const OCTAVE_BANDS: [f64; 7] = [125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0];
+// bandwidth of one octave
+const OCTAVE_Q: f64 = std::f64::consts::SQRT_2;
+
+// all times are in seconds, None when the curve doesn't fall far enough to measure it
+#[derive(Clone, Copy, Debug)]
+pub struct DecayReport {
+    pub edt: Option<f64>,
+    pub t20: Option<f64>,
+    pub t30: Option<f64>,
+    // best available estimate, t30 then t20 then edt
+    pub rt60: Option<f64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct DecayAnalysis {
+    pub broadband: DecayReport,
+    // (center frequency, report)
+    pub bands: Vec<(f64, DecayReport)>,
+}
+
+// feeds a unit impulse into every input of the node and averages its outputs
+pub fn impulse_response(node: &mut dyn AudioUnit64, sample_rate: f64, duration: f64) -> Vec<f64> {
+    node.set_sample_rate(sample_rate);
+    let length = (duration * sample_rate) as usize;
+
+    let mut input = vec![0.0; node.inputs()];
+    let mut output = vec![0.0; node.outputs()];
+    (0..length).map(|i| {
+        input.fill(if i == 0 { 1.0 } else { 0.0 });
+        node.tick(&input, &mut output);
+        output.iter().sum::<f64>() / output.len().max(1) as f64
+    }).collect()
+}
+
+// schroeder backward integration, in dB relative to the total energy
+pub fn energy_decay_curve(response: &[f64]) -> Vec<f64> {
+    let mut energy = 0.0;
+    let mut curve: Vec<f64> = response.iter().rev().map(|x| {
+        energy += x * x;
+        energy
+    }).collect();
+    curve.reverse();
+
+    let total = curve.first().copied().unwrap_or(0.0);
+    curve.iter().map(|e| {
+        if total > 0.0 && *e > 0.0 {
+            10.0 * (e / total).log10()
+        } else {
+            f64::NEG_INFINITY
+        }
+    }).collect()
+}
+
+// fits a line to the curve between start_db and end_db (both negative)
+// and extrapolates the time it takes to fall by 60dB
+pub fn decay_time(curve: &[f64], sample_rate: f64, start_db: f64, end_db: f64) -> Option<f64> {
+    let start = curve.iter().position(|db| *db <= start_db)?;
+    let end = curve.iter().position(|db| *db <= end_db)?;
+    if end <= start + 1 {
+        return None;
+    }
+
+    // least squares over the points in range
+    let n = (end - start) as f64;
+    let (mut sum_t, mut sum_db, mut sum_tt, mut sum_tdb) = (0.0, 0.0, 0.0, 0.0);
+    for (i, db) in curve[start..end].iter().enumerate() {
+        let t = (start + i) as f64 / sample_rate;
+        sum_t += t;
+        sum_db += db;
+        sum_tt += t * t;
+        sum_tdb += t * db;
+    }
+    let slope = (n * sum_tdb - sum_t * sum_db) / (n * sum_tt - sum_t * sum_t);
+
+    if slope < 0.0 {
+        Some(-60.0 / slope)
+    } else {
+        None
+    }
+}
+
+pub fn decay_report(response: &[f64], sample_rate: f64) -> DecayReport {
+    let curve = energy_decay_curve(response);
+    let edt = decay_time(&curve, sample_rate, 0.0, -10.0);
+    let t20 = decay_time(&curve, sample_rate, -5.0, -25.0);
+    let t30 = decay_time(&curve, sample_rate, -5.0, -35.0);
+
+    DecayReport {
+        edt,
+        t20,
+        t30,
+        rt60: t30.or(t20).or(edt),
+    }
+}
+
+// broadband and per octave band decay times of the node's impulse response.
+// duration should be comfortably longer than the decay being measured
+pub fn analyze_decay(node: &mut dyn AudioUnit64, sample_rate: f64, duration: f64) -> DecayAnalysis {
+    let response = impulse_response(node, sample_rate, duration);
+
+    let bands = OCTAVE_BANDS.iter()
+        .filter(|center| **center < 0.45 * sample_rate)
+        .map(|center| {
+            // two bandpass passes for steeper band edges
+            let coeffs = band_pass_coefficients(sample_rate, *center, OCTAVE_Q);
+            let mut first = BiquadFilter::new(coeffs);
+            let mut second = BiquadFilter::new(coeffs);
+            let band: Vec<f64> = response.iter()
+                .map(|x| second.process_sample(first.process_sample(*x)))
+                .collect();
+            (*center, decay_report(&band, sample_rate))
+        })
+        .collect();
+
+    DecayAnalysis {
+        broadband: decay_report(&response, sample_rate),
+        bands,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reverb::schroeder_reverb;
+
+    #[test]
+    fn exponential_decay_is_measured_exactly() {
+        // 60dB down after 1.5 seconds
+        let sample_rate = 44100.0;
+        let response: Vec<f64> = (0..(4.0 * sample_rate) as usize)
+            .map(|i| 10f64.powf(-3.0 * i as f64 / (1.5 * sample_rate)))
+            .collect();
+        let report = decay_report(&response, sample_rate);
+        for time in [report.edt, report.t20, report.t30, report.rt60] {
+            assert!((time.unwrap() - 1.5).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn schroeder_reverb_decays_in_two_seconds() {
+        let analysis = analyze_decay(&mut schroeder_reverb(44100.0, 2.0), 44100.0, 5.0);
+        let rt60 = analysis.broadband.rt60.unwrap();
+        assert!((rt60 - 2.0).abs() < 0.2, "rt60 was {}", rt60);
+    }
+}
@@ -0,0 +1,91 @@
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::filter::{BiquadFilter, second_order_lpf_coefficients};
+
+// mn3007-style line, enough for chorus and short delays
+pub const BBD_DEFAULT_STAGES: usize = 1024;
+// noise floor of the line before expansion, roughly -70dB
+const BBD_NOISE: f64 = 0.0003;
+// compander envelope time constant in seconds
+const COMPANDER_TIME: f64 = 0.01;
+const COMPANDER_FLOOR: f64 = 0.0001;
+
+#[derive(Clone, Copy)]
+pub enum DelayEngine {
+    DIGITAL,
+    // number of bbd stages
+    BBD(usize),
+}
+
+// analog bucket-brigade coloring for a delay line. a real bbd runs its clock at
+// stages / (2 * delay), so long delays lose bandwidth. the signal is compressed 2:1
+// before the line and expanded after it, which makes the line noise pump with the signal.
+#[derive(Clone)]
+pub struct Bbd {
+    sample_rate: f64,
+    stages: usize,
+    cutoff: f64,
+    anti_alias: BiquadFilter,
+    reconstruction: BiquadFilter,
+    compressor_env: f64,
+    expander_env: f64,
+    env_coeff: f64,
+    rng: StdRng,
+}
+
+impl Bbd {
+    pub fn new(sample_rate: f64, stages: usize, seed: u64) -> Self {
+        let stages = stages.max(1);
+        let cutoff = 0.45 * sample_rate;
+        let coeffs = second_order_lpf_coefficients(sample_rate, cutoff, 0.707);
+
+        Self {
+            sample_rate,
+            stages,
+            cutoff,
+            anti_alias: BiquadFilter::new(coeffs),
+            reconstruction: BiquadFilter::new(coeffs),
+            compressor_env: COMPANDER_FLOOR,
+            expander_env: COMPANDER_FLOOR,
+            env_coeff: (-1.0 / (COMPANDER_TIME * sample_rate)).exp(),
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    // retunes the filters to the clock rate needed for the given delay
+    pub fn set_delay(&mut self, delay_samples: f64) {
+        let delay_seconds = (delay_samples / self.sample_rate).max(1.0 / self.sample_rate);
+        let clock = self.stages as f64 / (2.0 * delay_seconds);
+        // the line is sampled at the clock rate, so it can't pass more than half of it
+        let cutoff = (0.45 * clock).min(0.45 * self.sample_rate);
+
+        // only recalculate when the change is audible, modulated lines call this every sample
+        if (cutoff - self.cutoff).abs() > 0.01 * self.cutoff {
+            self.cutoff = cutoff;
+            let coeffs = second_order_lpf_coefficients(self.sample_rate, cutoff, 0.707);
+            self.anti_alias.set_coefficients(coeffs);
+            self.reconstruction.set_coefficients(coeffs);
+        }
+    }
+
+    // input side: anti-alias filter, 2:1 compression and the soft clipping of the line
+    pub fn encode(&mut self, x: f64) -> f64 {
+        let x = self.anti_alias.process_sample(x);
+        self.compressor_env = follow(self.compressor_env, x.abs(), self.env_coeff);
+        let compressed = x / self.compressor_env.max(COMPANDER_FLOOR).sqrt();
+        compressed.tanh()
+    }
+
+    // output side: line noise, 1:2 expansion and reconstruction filter
+    pub fn decode(&mut self, y: f64) -> f64 {
+        let y = y + BBD_NOISE * self.rng.gen_range(-1.0..1.0);
+        self.expander_env = follow(self.expander_env, y.abs(), self.env_coeff);
+        let expanded = y * self.expander_env;
+        self.reconstruction.process_sample(expanded)
+    }
+}
+
+// one-pole envelope follower
+fn follow(env: f64, x: f64, coeff: f64) -> f64 {
+    coeff * env + (1.0 - coeff) * x
+}
@@ -1,59 +1,169 @@
-use fundsp::{audionode::AudioNode, prelude::{An, tan}, buffer, feedback};
-use numeric_array::typenum::{*, self};
-use rand::Rng;
+use fundsp::{audionode::AudioNode, prelude::An};
+use numeric_array::typenum::{self};
+use rand::{Rng, SeedableRng, rngs::StdRng};
 use circular_buffer::CircularBuffer;
 
-use crate::delay::{Delay, self};
+use crate::bbd::{DelayEngine, BBD_DEFAULT_STAGES};
+use crate::delay::Delay;
+use crate::lfo::{Lfo, LfoShape};
+use crate::seed::DEFAULT_SEED;
 
-const MAX_DELAY: f64 = 100.0;
+const MAX_VOICES: usize = 16;
+
+/// a single chorus voice where:
+///- delay_ms is the base delay in ms
+///- depth_ms is the modulation depth in ms
+///- rate_offset is added to the chorus rate, in Hz
+///- pan is in [-1, 1] (left to right)
+///- phase is the starting lfo phase in [0, 1), random when None
+#[derive(Clone, Copy)]
+pub struct ChorusVoice {
+    pub delay_ms: f64,
+    pub depth_ms: f64,
+    pub rate_offset: f64,
+    pub pan: f64,
+    pub phase: Option<f64>,
+}
+
+impl ChorusVoice {
+    pub fn new(delay_ms: f64, depth_ms: f64, rate_offset: f64, pan: f64) -> Self {
+        Self {
+            delay_ms: delay_ms.max(0.0),
+            depth_ms: depth_ms.max(0.0),
+            rate_offset,
+            pan: pan.clamp(-1.0, 1.0),
+            phase: None,
+        }
+    }
+
+    pub fn with_phase(mut self, phase: f64) -> Self {
+        self.phase = Some(phase.rem_euclid(1.0));
+        self
+    }
+
+    /// spreads `count` voices evenly around the base delay, rate and stereo field.
+    /// detune is the maximum rate offset in Hz, spread is the stereo width in [0, 1]
+    pub fn spread(count: usize, delay_ms: f64, depth_ms: f64, detune: f64, spread: f64) -> Vec<ChorusVoice> {
+        let count = count.clamp(1, MAX_VOICES);
+        let spread = spread.clamp(0.0, 1.0);
+
+        let mut voices = Vec::with_capacity(count);
+        for i in 0..count {
+            // position of the voice in [-1, 1], a single voice sits in the middle
+            let position = if count == 1 {
+                0.0
+            } else {
+                2.0 * i as f64 / (count - 1) as f64 - 1.0
+            };
+            voices.push(ChorusVoice::new(
+                delay_ms * (1.0 + 0.25 * position),
+                depth_ms,
+                detune * position,
+                spread * position,
+            ));
+        }
+        voices
+    }
+
+    // balance pan law, a centered voice goes to both channels at full gain
+    fn gains(&self) -> (f64, f64) {
+        ((1.0 - self.pan).min(1.0), (1.0 + self.pan).min(1.0))
+    }
+}
+
+// fixed-rate ensemble settings, modelled after the four buttons of the classic
+// two-line bbd dimension units. (delay ms, depth ms, rate Hz)
+const DIMENSION_MODES: [(f64, f64, f64); 4] = [
+    (6.0, 0.6, 0.25),
+    (6.0, 1.0, 0.25),
+    (6.0, 1.5, 0.25),
+    (6.0, 2.2, 0.5),
+];
 
 #[derive(Clone)]
 pub struct Chorus {
-    rate: f64,
-    delay_ms: f64,
     mix: f64,
-    sample_rate: f64,
-    delay_samples: usize,
-    delay_1: Delay,
-    delay_2: Delay,
-    delay_3: Delay,
-    phase1: f64,
-    phase2: f64,
-    phase3: f64,
-    calculated_depth: f64,
+    dry: f64,
+    voices: Vec<ChorusVoice>,
+    delays: Vec<Delay>,
+    delay_samples: Vec<usize>,
+    depths: Vec<f64>,
+    lfos: Vec<Lfo>,
+    feedback_delay: usize,
     feedback: f64,
     // 3 second buffer at 44.1khz
     buffer: Box<CircularBuffer::<{3*44100}, f64>>,
-    count: usize,
+    sample_rate: f64,
+    seed: u64,
 }
 
 
 impl Chorus {
     //time represented in ms. sr is sample rate
-    fn new(sample_rate: f64, depth: f64, rate: f64, delay_ms: f64, mix: f64, feedback: f64) -> Chorus {
-        // convert delay time from ms to samples
-        let delay_samples: usize = ((delay_ms / 1000.0) * sample_rate) as usize;
-        
-        // delay lines
-        let delay_1 = Delay::new(delay_samples, 0.0);
-        let delay_2 = Delay::new(delay_samples, 0.0);
-        let delay_3 = Delay::new(delay_samples, 0.0);
-
-        // generate3 random phase offsets in (0, 2pi)
-        let mut rng = rand::thread_rng();
-        let phase1 = 0.0;//rng.gen_range(0.0..2.0 * std::f64::consts::PI);
-        let phase2 = rng.gen_range(0.0..2.0 * std::f64::consts::PI);
-        let phase3 = rng.gen_range(0.0..2.0 * std::f64::consts::PI);
-        
-        let mut calculated_depth = depth * sample_rate / 1000.0;
-        if calculated_depth > delay_samples as f64 {
-            println!("calculated_depth too high. Setting to delay_samples/2");
-            calculated_depth = delay_samples as f64 / 2.0;
+    pub fn new(sample_rate: f64, voices: Vec<ChorusVoice>, rate: f64, mix: f64, feedback: f64, seed: u64) -> Chorus {
+        Self::with_layout(sample_rate, voices, rate, LfoShape::SINE, mix, 1.0, feedback, seed)
+    }
+
+    // pure modulated delay, the output is 100% wet
+    pub fn new_vibrato(sample_rate: f64, depth_ms: f64, rate: f64, shape: LfoShape) -> Chorus {
+        let voice = ChorusVoice::new(depth_ms, depth_ms, 0.0, 0.0).with_phase(0.0);
+        Self::with_layout(sample_rate, vec![voice], rate, shape, 1.0, 0.0, 0.0, 0)
+    }
+
+    // two fixed-rate voices in opposite phase, panned hard left and right.
+    // mode is in [1, 4], higher modes give a wider, deeper ensemble
+    pub fn new_dimension(sample_rate: f64, mode: usize) -> Chorus {
+        let (delay_ms, depth_ms, rate) = DIMENSION_MODES[mode.clamp(1, 4) - 1];
+        let voices = vec![
+            ChorusVoice::new(delay_ms, depth_ms, 0.0, -1.0).with_phase(0.0),
+            ChorusVoice::new(delay_ms, depth_ms, 0.0, 1.0).with_phase(0.5),
+        ];
+        Self::with_layout(sample_rate, voices, rate, LfoShape::TRIANGLE, 1.0, 1.0, 0.0, 0)
+    }
+
+    // mix scales the summed voices, dry scales the input that is added to both channels
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_layout(sample_rate: f64, voices: Vec<ChorusVoice>, rate: f64, shape: LfoShape, mix: f64, dry: f64, feedback: f64, seed: u64) -> Chorus {
+        let mut voices = voices;
+        if voices.is_empty() {
+            voices.push(ChorusVoice::new(20.0, 5.0, 0.0, 0.0));
         }
-        println!("base delay: {} samples", delay_samples);
-        println!("calculated depth: {} samples", calculated_depth);
-        println!("min depth: {} samples", delay_samples as f64 - calculated_depth);
-        println!("max depth: {} samples", delay_samples as f64 + calculated_depth);
+        if voices.len() > MAX_VOICES {
+            println!("Too many chorus voices. Using the first {}", MAX_VOICES);
+            voices.truncate(MAX_VOICES);
+        }
+
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let mut delays = Vec::with_capacity(voices.len());
+        let mut delay_samples = Vec::with_capacity(voices.len());
+        let mut depths = Vec::with_capacity(voices.len());
+        let mut lfos = Vec::with_capacity(voices.len());
+
+        for (i, voice) in voices.iter().enumerate() {
+            // convert delay time from ms to samples
+            let samples = ((voice.delay_ms / 1000.0) * sample_rate) as usize;
+
+            let mut depth = voice.depth_ms * sample_rate / 1000.0;
+            if depth > samples as f64 {
+                println!("calculated_depth too high. Setting to delay_samples/2");
+                depth = samples as f64 / 2.0;
+            }
+
+            // the first voice starts at zero phase, the rest get a random offset in (0, 1)
+            let phase = match voice.phase {
+                Some(phase) => phase,
+                None if i == 0 => 0.0,
+                None => rng.gen_range(0.0..1.0),
+            };
+
+            delays.push(Delay::new(samples, 0.0));
+            delay_samples.push(samples);
+            depths.push(depth);
+            lfos.push(Lfo::new(sample_rate, rate + voice.rate_offset, shape, phase));
+        }
+
+        let feedback_delay = delay_samples.iter().sum::<usize>() / delay_samples.len();
 
         let feedback = feedback.clamp(0.0, 0.9999);
 
@@ -63,25 +173,55 @@ impl Chorus {
         }
 
         Chorus {
-            rate,
-            delay_ms,
             mix,
-            sample_rate,
+            dry,
+            voices,
+            delays,
             delay_samples,
-            delay_1: delay_1,
-            delay_2: delay_2,
-            delay_3: delay_3,
-            phase1: phase1,
-            phase2: phase2,
-            phase3: phase3,
-            calculated_depth,
+            depths,
+            lfos,
+            feedback_delay,
             feedback,
             buffer,
-            count: 0,
+            sample_rate,
+            seed,
         }
     }
+
+    // switches every voice to the given delay engine
+    pub fn with_engine(mut self, engine: DelayEngine) -> Chorus {
+        for (i, delay) in self.delays.iter_mut().enumerate() {
+            delay.set_engine(self.sample_rate, engine, self.seed.wrapping_add(i as u64));
+        }
+        self
+    }
+
+    pub fn process_sample(&mut self, x: f64) -> (f64, f64) {
+        let new_x = x + self.feedback * self.buffer.get(self.feedback_delay).unwrap();
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for i in 0..self.voices.len() {
+            let offset = self.lfos[i].tick() * self.depths[i] / 2.0;
+            let delay = (self.delay_samples[i] as f64 + offset).max(0.0);
+            let wet = self.delays[i].process_sample_fractional(new_x, delay);
+
+            let (gain_l, gain_r) = self.voices[i].gains();
+            left += gain_l * wet;
+            right += gain_r * wet;
+        }
+
+        // mix * (1/n) * (delay1 + ... + delayn) + dry * x, per channel
+        let scale = self.mix / self.voices.len() as f64;
+        let left = scale * left + self.dry * new_x;
+        let right = scale * right + self.dry * new_x;
+
+        self.buffer.push_front(0.5 * (left + right));
+        (left, right)
+    }
 }
 
+// mono output, the two channels are averaged
 impl AudioNode for Chorus {
     const ID: u64 = 9998;
     type Sample = f64;
@@ -96,42 +236,39 @@ impl AudioNode for Chorus {
             &mut self,
             input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
         ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
+        let (left, right) = self.process_sample(input[0]);
+        [0.5 * (left + right)].into()
+    }
+}
 
-        let x = input[0] as f64;
+// the same chorus with the panned voices on two outputs
+#[derive(Clone)]
+pub struct StereoChorus {
+    chorus: Chorus,
+}
 
-        self.phase1 += 2.0 * std::f64::consts::PI * self.rate / self.sample_rate;  
-        if self.phase1 > 2.0 * std::f64::consts::PI {
-            self.phase1 -= 2.0 * std::f64::consts::PI;
-        }
-        // repeat for all other phases
-        self.phase2 += 2.0 * std::f64::consts::PI * self.rate / self.sample_rate;
-        if self.phase2 > 2.0 * std::f64::consts::PI {
-            self.phase2 -= 2.0 * std::f64::consts::PI;
-        }
+impl StereoChorus {
+    pub fn new(chorus: Chorus) -> Self {
+        Self { chorus }
+    }
+}
 
-        self.phase3 += 2.0 * std::f64::consts::PI * self.rate / self.sample_rate;
-        if self.phase3 > 2.0 * std::f64::consts::PI {
-            self.phase3 -= 2.0 * std::f64::consts::PI;
-        }
-        
-        let offset1 = ((self.phase1.sin()) * self.calculated_depth / 2.0).round() as i32;
-        let offset2 = ((self.phase2.sin()) * self.calculated_depth / 2.0).round() as i32;
-        let offset3 = ((self.phase3.sin()) * self.calculated_depth / 2.0).round() as i32;
-        //println!("sample: {}, offset1: {}", self.count, offset1);
-        
-        let new_x = x + self.feedback * self.buffer.get(self.delay_samples).unwrap();
-        //println!("sample: {}, delay: {}", self.count, (self.delay_samples as i32 + offset1));
-        // mix * (1/3) * (delay1 + delay2 + delay3) + (1 - mix) * x
-        let y = 
-        self.mix * 1.0/3.0 * (
-            self.delay_1.process_sample(new_x, (self.delay_samples as i32 + offset1) as usize)
-            + self.delay_2.process_sample(new_x, (self.delay_samples as i32 + offset2) as usize)
-            + self.delay_3.process_sample(new_x, (self.delay_samples as i32 + offset3) as usize)
-        ) + new_x;
-
-        self.buffer.push_front(y);
-        self.count += 1;
-        [y].into()
+impl AudioNode for StereoChorus {
+    const ID: u64 = 9974;
+    type Sample = f64;
+    type Inputs = typenum::U1;
+    type Outputs = typenum::U2;
+    type Setting = f64;
+
+    fn reset(&mut self) {
+    }
+
+    fn tick(
+            &mut self,
+            input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
+        ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
+        let (left, right) = self.chorus.process_sample(input[0]);
+        [left, right].into()
     }
 }
 
@@ -142,5 +279,75 @@ impl AudioNode for Chorus {
 ///- mix is in [0, 1]
 ///- feedback is in [0, 0.9999]
 pub fn my_chorus(sample_rate: f64, depth: f64, rate: f64, delay_ms: f64, mix: f64, feedback: f64) -> An<Chorus> {
-    An(Chorus::new(sample_rate, depth, rate, delay_ms, mix, feedback))
-}
\ No newline at end of file
+    // three centered voices on the same delay, only their lfo phases differ
+    let voices = vec![ChorusVoice::new(delay_ms, depth, 0.0, 0.0); 3];
+    An(Chorus::new(sample_rate, voices, rate, mix, feedback, DEFAULT_SEED))
+}
+
+/// created a new n-voice stereo chorus effect where:
+///- voices is in [1, 16]
+///- depth is in ms
+///- rate is in Hz
+///- delay is in ms
+///- detune is the maximum per-voice rate offset in Hz
+///- spread is the stereo width in [0, 1]
+///- mix is in [0, 1]
+///- feedback is in [0, 0.9999]
+///- seed picks the random lfo phases
+#[allow(clippy::too_many_arguments)]
+pub fn my_stereo_chorus(sample_rate: f64, voices: usize, depth: f64, rate: f64, delay_ms: f64, detune: f64, spread: f64, mix: f64, feedback: f64, seed: u64) -> An<StereoChorus> {
+    let voices = ChorusVoice::spread(voices, delay_ms, depth, detune, spread);
+    An(StereoChorus::new(Chorus::new(sample_rate, voices, rate, mix, feedback, seed)))
+}
+
+/// created a new vibrato effect (100% wet modulated delay) where:
+///- depth is in ms
+///- rate is in Hz
+pub fn my_vibrato(sample_rate: f64, depth: f64, rate: f64, shape: LfoShape) -> An<Chorus> {
+    An(Chorus::new_vibrato(sample_rate, depth, rate, shape))
+}
+
+/// created a new dimension-style stereo ensemble where:
+///- mode is in [1, 4]
+pub fn my_dimension(sample_rate: f64, mode: usize) -> An<StereoChorus> {
+    An(StereoChorus::new(Chorus::new_dimension(sample_rate, mode)))
+}
+
+/// same as my_chorus, but every voice runs through a bucket-brigade line
+pub fn my_bbd_chorus(sample_rate: f64, depth: f64, rate: f64, delay_ms: f64, mix: f64, feedback: f64) -> An<Chorus> {
+    let voices = vec![ChorusVoice::new(delay_ms, depth, 0.0, 0.0); 3];
+    An(Chorus::new(sample_rate, voices, rate, mix, feedback, DEFAULT_SEED).with_engine(DelayEngine::BBD(BBD_DEFAULT_STAGES)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(chorus: &mut Chorus) -> Vec<u64> {
+        let mut output = Vec::new();
+        for i in 0..22050 {
+            let (left, right) = chorus.process_sample((i as f64 * 0.05).sin());
+            output.push(left.to_bits());
+            output.push(right.to_bits());
+        }
+        output
+    }
+
+    #[test]
+    fn same_seed_renders_bit_for_bit() {
+        let voices = ChorusVoice::spread(6, 15.0, 4.0, 0.2, 1.0);
+        let mut first = Chorus::new(44100.0, voices.clone(), 0.5, 0.7, 0.2, 42).with_engine(DelayEngine::BBD(BBD_DEFAULT_STAGES));
+        let mut second = Chorus::new(44100.0, voices, 0.5, 0.7, 0.2, 42).with_engine(DelayEngine::BBD(BBD_DEFAULT_STAGES));
+        assert_eq!(render(&mut first), render(&mut second));
+    }
+
+    #[test]
+    fn detune_past_the_rate_keeps_the_voice_moving() {
+        let voices = ChorusVoice::spread(3, 15.0, 4.0, 1.0, 1.0);
+        let mut chorus = Chorus::new(44100.0, voices, 0.2, 0.7, 0.0, 0);
+        // the first voice runs at 0.2 - 1.0 Hz
+        let values: Vec<f64> = (0..44100).map(|_| chorus.lfos[0].tick()).collect();
+        let (min, max) = values.iter().fold((f64::MAX, f64::MIN), |(min, max), v| (min.min(*v), max.max(*v)));
+        assert!(min < -0.99 && max > 0.99, "lfo stayed in [{}, {}]", min, max);
+    }
+}
@@ -3,10 +3,11 @@ use fundsp::{audionode::AudioNode, prelude::An};
 use numeric_array::typenum::{*, self};
 use rand::Rng;
 
-use crate::filter::{BiquadCoefficients, BiquadFilter};
+use crate::filter::{BiquadCoefficients, BiquadFilter, OnePole, one_pole_coefficient};
 
 const MAX_INDEX: usize = 41100;
 
+// sign of the feedback, or of the feedforward gain for feedforward combs
 #[derive(Clone, Copy)]
 pub enum CombType {
     POSITIVE,
@@ -16,13 +17,25 @@ pub enum CombType {
 #[derive(Clone)]
 pub struct CombFilter {
     buffer: Box<CircularBuffer::<MAX_INDEX, f64>>,
-    x_buffer: Box<CircularBuffer::<MAX_INDEX, f64>>,
     buffer_index: usize,
     delay: usize,
     feedback: f64,
     comb_type: CombType,
-    use_lpf: bool,
+    // input history of the lowpass comb
+    x_buffer: Option<Box<CircularBuffer::<MAX_INDEX, f64>>>,
     lpf_g: f64,
+    // one-pole lowpass in the feedback path
+    damping: Option<OnePole>,
+    // first order allpass after the damping, adds a fractional delay for fine tuning
+    tuning: Option<BiquadFilter>,
+    // universal comb, v is what goes into the delay line:
+    // v(n) = x(n) + fb * v(n - delay)
+    // y(n) = blend * v(n) + ff * v(n - delay)
+    // a feedback comb has blend 1.0 and ff 0.0
+    blend: f64,
+    feedforward: f64,
+    // scales the output so the peak gain is 1.0 in normalized mode
+    output_gain: f64,
 }
 
 impl CombFilter {
@@ -42,22 +55,29 @@ impl CombFilter {
             buffer.push_back(0.0);
         }
 
-        let mut x_buf = CircularBuffer::<MAX_INDEX, f64>::boxed();
-        if use_lpf {
+        let x_buffer = if use_lpf {
+            let mut x_buf = CircularBuffer::<MAX_INDEX, f64>::boxed();
             for _ in 0..MAX_INDEX {
                 x_buf.push_back(0.0);
             }
-        }
-        
+            Some(x_buf)
+        } else {
+            None
+        };
+
         Self {
             buffer: buffer,
-            x_buffer: x_buf,
             buffer_index: 0,
             delay,
             feedback,
             comb_type,
-            use_lpf,
+            x_buffer,
             lpf_g,
+            damping: None,
+            tuning: None,
+            blend: 1.0,
+            feedforward: 0.0,
+            output_gain: 1.0,
         }
     }
 
@@ -65,39 +85,147 @@ impl CombFilter {
         Self::new(delay, feedback, comb_type, false, 0.0)
     }
 
-    pub fn new_lpf_comb(delay: usize, feedback: f64, lpf_g: f64) -> Self {
-        Self::new(delay, feedback, CombType::POSITIVE, true, lpf_g)
+    // lowpass comb whose lowpass coefficient is taken from a one-pole -3dB at cutoff Hz
+    pub fn new_lpf_comb(sample_rate: f64, delay: usize, feedback: f64, cutoff: f64) -> Self {
+        Self::new(delay, feedback, CombType::POSITIVE, true, one_pole_coefficient(sample_rate, cutoff))
+    }
+
+    // the lowpass-feedback comb of freeverb, a one-pole lowpass with its pole at damping in [0, 1)
+    // filters what goes back into the line:
+    // s(n) = (1 - g) * v(n - delay) + g * s(n - 1)
+    // v(n) = x(n) + fb * s(n)
+    pub fn new_one_pole_comb(delay: usize, feedback: f64, damping: f64) -> Self {
+        let mut comb = Self::new_comb(delay, feedback, CombType::POSITIVE);
+        comb.damping = Some(OnePole::from_coefficient(damping));
+        comb
+    }
+
+    // one-pole lowpass-feedback comb whose damping filter is -3dB at cutoff Hz
+    pub fn new_damped_comb(sample_rate: f64, delay: usize, feedback: f64, cutoff: f64) -> Self {
+        let mut comb = Self::new_comb(delay, feedback, CombType::POSITIVE);
+        comb.damping = Some(OnePole::new(sample_rate, cutoff));
+        comb
+    }
+
+    // y(n) = x(n) +- gain * x(n - delay), with the sign given by comb_type
+    pub fn new_feedforward(delay: usize, gain: f64, comb_type: CombType) -> Self {
+        let mut comb = Self::new_comb(delay, 0.0, CombType::POSITIVE);
+        comb.feedforward = match comb_type {
+            CombType::POSITIVE => gain,
+            CombType::NEGATIVE => -gain,
+        };
+        comb
+    }
+
+    // dattorro's universal comb, the gains are signed and feedback has to stay in (-1, 1).
+    // (1, 0, fb) is a feedback comb, (1, ff, 0) a feedforward comb and (-g, 1, g) an allpass
+    pub fn new_universal(delay: usize, blend: f64, feedforward: f64, feedback: f64) -> Self {
+        let comb_type = if feedback < 0.0 { CombType::NEGATIVE } else { CombType::POSITIVE };
+        let mut comb = Self::new_comb(delay, feedback.abs(), comb_type);
+        comb.blend = blend;
+        comb.feedforward = feedforward;
+        comb
+    }
+
+    // scales the output by the inverse of the peak gain, so the resonances stay at unity
+    // however close the feedback gets to 1.0
+    pub fn with_normalization(mut self) -> Self {
+        self.output_gain = 1.0 / self.peak_gain();
+        self
+    }
+
+    // largest gain of the comb over all frequencies, reached where the delay is in or out of phase
+    pub fn peak_gain(&self) -> f64 {
+        let feedback = match self.comb_type {
+            CombType::POSITIVE => self.feedback,
+            CombType::NEGATIVE => -self.feedback,
+        };
+        let in_phase = (self.blend + self.feedforward).abs() / (1.0 - feedback).abs().max(f64::EPSILON);
+        let out_of_phase = (self.blend - self.feedforward).abs() / (1.0 + feedback).abs().max(f64::EPSILON);
+        in_phase.max(out_of_phase).max(f64::EPSILON)
+    }
+
+    // an lpf comb switches over to the one-pole damping
+    pub fn set_damping_cutoff(&mut self, sample_rate: f64, cutoff: f64) {
+        self.x_buffer = None;
+        match &mut self.damping {
+            Some(damping) => damping.set_cutoff(sample_rate, cutoff),
+            None => self.damping = Some(OnePole::new(sample_rate, cutoff)),
+        }
+    }
+
+    // lengthens the feedback loop by delay samples, in [0.1, 2.0), with allpass interpolation.
+    // unlike a fractional read this doesn't lowpass the loop, so it can be used for tuning
+    pub fn set_fractional_delay(&mut self, delay: f64) {
+        // thiran's first order allpass, exact phase delay at dc
+        let delay = delay.clamp(0.1, 1.999);
+        let c = (1.0 - delay) / (1.0 + delay);
+        self.tuning = Some(BiquadFilter::new(BiquadCoefficients::new(c, 1.0, 0.0, c, 0.0, 1.0, 0.0)));
     }
 
     pub fn process_sample(&mut self, x: f64) -> f64 {
-        let delayed_sample = self.buffer.get(self.delay).unwrap();
-        let mut y = 0.0f64;
-        if self.use_lpf {
-            y = x 
-            + delayed_sample * self.feedback
-            // the lpf part
-            - self.lpf_g * self.x_buffer.get(self.delay + 1).unwrap() 
-            + self.lpf_g * self.buffer.get(1).unwrap();
-        } else {
-            match self.comb_type {
-                CombType::POSITIVE => {
-                    y = x + delayed_sample * self.feedback;
-                },
-                CombType::NEGATIVE => {
-                    y = x - delayed_sample * self.feedback;
-                }
+        let delayed_sample = *self.buffer.get(self.delay).unwrap();
+        self.process_delayed(x, delayed_sample, self.delay as f64)
+    }
+
+    // same as process_sample, but the delay is read with linear interpolation,
+    // so it can be modulated without clicks
+    pub fn process_sample_fractional(&mut self, x: f64, delay: f64) -> f64 {
+        let delayed_sample = self.peek_fractional(delay);
+        self.process_delayed(x, delayed_sample, delay)
+    }
+
+    // the sample that leaves the delay line on the next call to process_sample
+    pub fn peek(&self) -> f64 {
+        *self.buffer.get(self.delay).unwrap()
+    }
+
+    // the sample that leaves the delay line on the next call to process_sample_fractional
+    pub fn peek_fractional(&self, delay: f64) -> f64 {
+        read_fractional(&self.buffer, delay)
+    }
+
+    pub fn delay(&self) -> usize {
+        self.delay
+    }
+
+    fn process_delayed(&mut self, x: f64, delayed_sample: f64, delay: f64) -> f64 {
+        let fed_back = match &mut self.damping {
+            Some(damping) => damping.process_sample(delayed_sample),
+            None => delayed_sample,
+        };
+        let fed_back = match self.comb_type {
+            CombType::POSITIVE => fed_back * self.feedback,
+            CombType::NEGATIVE => -fed_back * self.feedback,
+        };
+        // the lpf part of the lpf comb, from the input and line history
+        let fed_back = match &mut self.x_buffer {
+            Some(x_buffer) => {
+                let lowpass = self.lpf_g * (self.buffer.get(1).unwrap() - read_fractional(x_buffer, delay + 1.0));
+                x_buffer.push_front(x);
+                fed_back + lowpass
             }
-        }
-        
-        self.buffer.push_front(y);
-        if self.use_lpf {
-            self.x_buffer.push_front(x);
-        }
+            None => fed_back,
+        };
+        let fed_back = match &mut self.tuning {
+            Some(tuning) => tuning.process_sample(fed_back),
+            None => fed_back,
+        };
+        let v = x + fed_back;
+
+        self.buffer.push_front(v);
         self.buffer_index = (self.buffer_index + 1) % self.delay;
-        y
+        (self.blend * v + self.feedforward * delayed_sample) * self.output_gain
     }
 }
 
+fn read_fractional(buffer: &CircularBuffer::<MAX_INDEX, f64>, delay: f64) -> f64 {
+    let delay = delay.clamp(0.0, (MAX_INDEX - 2) as f64);
+    let index = delay.floor() as usize;
+    let frac = delay - index as f64;
+    buffer.get(index).unwrap() * (1.0 - frac) + buffer.get(index + 1).unwrap() * frac
+}
+
 impl AudioNode for CombFilter {
     const ID: u64 = 9997;
     type Sample = f64;
@@ -107,28 +235,130 @@ impl AudioNode for CombFilter {
 
     fn reset(&mut self) {
         self.buffer_index = 0;
+        if let Some(damping) = &mut self.damping {
+            damping.reset();
+        }
+        if let Some(tuning) = &mut self.tuning {
+            tuning.reset();
+        }
     }
 
     fn tick(
             &mut self,
             input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
         ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
-        let delayed_sample = self.buffer.get(self.delay).unwrap();
-        let mut y = 0.0f64;
-        match self.comb_type {
-            CombType::POSITIVE => {
-                y = input[0] + delayed_sample * self.feedback;
-            },
-            CombType::NEGATIVE => {
-                y = input[0] - delayed_sample * self.feedback;
-            }
-        }
-        self.buffer.push_front(y);
-        self.buffer_index = (self.buffer_index + 1) % self.delay;
+        let y = self.process_sample(input[0]);
         [y].into()
     }
 }
 
 pub fn my_comb(delay: usize, feedback: f64, comb_type: CombType) -> An<CombFilter> {
     An(CombFilter::new(delay, feedback, comb_type, false, 0.0))
-}
\ No newline at end of file
+}
+
+/// created a new feedforward comb where:
+///- delay is in samples
+///- gain is added (POSITIVE) or subtracted (NEGATIVE) from the input
+pub fn my_feedforward_comb(delay: usize, gain: f64, comb_type: CombType) -> An<CombFilter> {
+    An(CombFilter::new_feedforward(delay, gain, comb_type))
+}
+
+/// created a new universal comb where:
+///- delay is in samples
+///- blend, feedforward and feedback are signed gains, feedback in (-1, 1)
+///- normalized scales the output so the peak gain is 1.0
+pub fn my_universal_comb(delay: usize, blend: f64, feedforward: f64, feedback: f64, normalized: bool) -> An<CombFilter> {
+    let comb = CombFilter::new_universal(delay, blend, feedforward, feedback);
+    An(if normalized { comb.with_normalization() } else { comb })
+}
+
+/// created a new lowpass-feedback comb where:
+///- delay is in samples
+///- feedback is in [0, 1]
+///- cutoff is the damping filter's -3dB point in Hz
+pub fn my_damped_comb(sample_rate: f64, delay: usize, feedback: f64, cutoff: f64) -> An<CombFilter> {
+    An(CombFilter::new_damped_comb(sample_rate, delay, feedback, cutoff))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input() -> Vec<f64> {
+        (0..4000).map(|i| if i % 1000 == 0 { 1.0 } else { 0.01 * (i as f64 * 0.1).sin() }).collect()
+    }
+
+    fn assert_graph_matches_direct(comb: CombFilter) {
+        let mut direct = comb.clone();
+        let mut graph = An(comb);
+        for x in input() {
+            assert_eq!(graph.filter_mono(x).to_bits(), direct.process_sample(x).to_bits());
+        }
+    }
+
+    #[test]
+    fn graph_matches_direct_processing() {
+        assert_graph_matches_direct(CombFilter::new_comb(99, 0.7, CombType::POSITIVE));
+        assert_graph_matches_direct(CombFilter::new_comb(99, 0.7, CombType::NEGATIVE));
+        assert_graph_matches_direct(CombFilter::new_lpf_comb(44100.0, 99, 0.7, 3000.0));
+        assert_graph_matches_direct(CombFilter::new_one_pole_comb(99, 0.7, 0.4));
+        assert_graph_matches_direct(CombFilter::new_damped_comb(44100.0, 99, 0.7, 3000.0));
+        assert_graph_matches_direct(CombFilter::new_feedforward(99, 0.7, CombType::NEGATIVE));
+        assert_graph_matches_direct(CombFilter::new_universal(99, 0.5, 0.7, -0.6).with_normalization());
+    }
+
+    #[test]
+    fn universal_comb_covers_the_other_modes() {
+        let mut feedback = CombFilter::new_comb(99, 0.7, CombType::NEGATIVE);
+        let mut universal = CombFilter::new_universal(99, 1.0, 0.0, -0.7);
+        let mut feedforward = CombFilter::new_feedforward(99, 0.7, CombType::POSITIVE);
+        let mut fir = CombFilter::new_universal(99, 1.0, 0.7, 0.0);
+        for x in input() {
+            assert_eq!(feedback.process_sample(x), universal.process_sample(x));
+            assert_eq!(feedforward.process_sample(x), fir.process_sample(x));
+        }
+    }
+
+    #[test]
+    fn normalized_comb_peaks_at_unity() {
+        // a sine right on a resonance of the comb
+        let delay = 99;
+        let period = (delay + 1) as f64;
+        for feedback in [0.5, 0.9, 0.99] {
+            let mut comb = CombFilter::new_comb(delay, feedback, CombType::POSITIVE).with_normalization();
+            let peak = (0..100000)
+                .map(|i| comb.process_sample((2.0 * std::f64::consts::PI * i as f64 / period).sin()).abs())
+                .skip(90000)
+                .fold(0.0, f64::max);
+            assert!(peak <= 1.0 + 1e-6 && peak > 0.9, "peak was {}", peak);
+        }
+    }
+
+    #[test]
+    fn lowpass_comb_differs_from_plain_comb_in_a_graph() {
+        let mut plain = my_comb(99, 0.7, CombType::POSITIVE);
+        let mut damped = my_damped_comb(44100.0, 99, 0.7, 1000.0);
+        let differs = input().iter().any(|x| plain.filter_mono(*x) != damped.filter_mono(*x));
+        assert!(differs);
+    }
+
+    #[test]
+    fn lpf_comb_is_tuned_by_the_fractional_delay() {
+        let mut plain = CombFilter::new_lpf_comb(44100.0, 99, 0.7, 3000.0);
+        let mut tuned = plain.clone();
+        tuned.set_fractional_delay(0.5);
+        let outputs: Vec<(f64, f64)> = input().iter().map(|x| (plain.process_sample(*x), tuned.process_sample(*x))).collect();
+        assert!(outputs.iter().all(|(a, b)| a.is_finite() && b.is_finite()));
+        assert!(outputs.iter().any(|(a, b)| a != b));
+    }
+
+    #[test]
+    fn damping_cutoff_is_three_db_down() {
+        let sample_rate = 44100.0;
+        let cutoff = 2000.0;
+        let g = OnePole::new(sample_rate, cutoff).coefficient();
+        let w = 2.0 * std::f64::consts::PI * cutoff / sample_rate;
+        let magnitude = (1.0 - g) / (1.0 - 2.0 * g * w.cos() + g * g).sqrt();
+        assert!((magnitude - 0.5f64.sqrt()).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,379 @@
+use std::{collections::VecDeque, path::Path, sync::Arc};
+
+use fundsp::{audionode::AudioNode, prelude::{An, split}, wave::Wave64};
+use numeric_array::typenum::{self, U1, U2};
+use rustfft::{Fft, FftPlanner, num_complex::Complex};
+
+use crate::delay::{Delay, MAX_DELAY};
+
+#[derive(Clone)]
+pub struct ImpulseResponse {
+    sample_rate: f64,
+    channels: Vec<Vec<f64>>,
+}
+
+impl ImpulseResponse {
+    pub fn new(sample_rate: f64, channels: Vec<Vec<f64>>) -> Self {
+        Self {
+            sample_rate,
+            channels,
+        }
+    }
+
+    // 1 channel is mono, 2 channels are left/right,
+    // 4 channels are true stereo in the order LL, LR, RL, RR
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let wave = Wave64::load(path)?;
+        let channels = (0..wave.channels())
+            .map(|channel| (0..wave.len()).map(|i| wave.at(channel, i)).collect())
+            .collect();
+        Ok(Self::new(wave.sample_rate(), channels))
+    }
+
+    pub fn len(&self) -> usize {
+        self.channels.iter().map(|channel| channel.len()).max().unwrap_or(0)
+    }
+
+    // cuts the silence before the first and after the last sample above threshold_db
+    // (relative to the peak), then shortens the response to max_seconds if given
+    pub fn trim(mut self, threshold_db: f64, max_seconds: Option<f64>) -> Self {
+        let peak = self.peak();
+        if peak == 0.0 {
+            return self;
+        }
+        let threshold = peak * 10f64.powf(threshold_db.min(0.0) / 20.0);
+
+        let above = |i: &usize| self.channels.iter().any(|channel| channel.get(*i).is_some_and(|x| x.abs() >= threshold));
+        let start = (0..self.len()).find(above).unwrap_or(0);
+        let mut end = (0..self.len()).rev().find(above).map_or(self.len(), |i| i + 1);
+        if let Some(max_seconds) = max_seconds {
+            end = end.min(start + (max_seconds.max(0.0) * self.sample_rate) as usize);
+        }
+
+        for channel in self.channels.iter_mut() {
+            channel.truncate(end);
+            channel.drain(..start.min(channel.len()));
+        }
+        self
+    }
+
+    // scales the response so its loudest channel has unit energy
+    pub fn normalize(mut self) -> Self {
+        let energy = self.channels.iter()
+            .map(|channel| channel.iter().map(|x| x * x).sum::<f64>())
+            .fold(0.0, f64::max);
+        if energy > 0.0 {
+            let scale = 1.0 / energy.sqrt();
+            for channel in self.channels.iter_mut() {
+                channel.iter_mut().for_each(|x| *x *= scale);
+            }
+        }
+        self
+    }
+
+    // linear interpolation is enough here, the response is only played back once
+    pub fn resample(mut self, sample_rate: f64) -> Self {
+        if sample_rate == self.sample_rate {
+            return self;
+        }
+        let ratio = self.sample_rate / sample_rate;
+        for channel in self.channels.iter_mut() {
+            let length = (channel.len() as f64 / ratio) as usize;
+            *channel = (0..length).map(|i| {
+                let position = i as f64 * ratio;
+                let index = position.floor() as usize;
+                let frac = position - index as f64;
+                let a = channel[index];
+                let b = *channel.get(index + 1).unwrap_or(&0.0);
+                a + (b - a) * frac
+            }).collect();
+        }
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    fn peak(&self) -> f64 {
+        self.channels.iter().flatten().fold(0.0, |peak, x| peak.max(x.abs()))
+    }
+}
+
+// uniformly partitioned overlap-save convolution of one input with one response.
+// with zero_latency the first partition is convolved directly in the time domain,
+// otherwise the output comes one block late
+#[derive(Clone)]
+struct Convolver {
+    block: usize,
+    fft: Arc<dyn Fft<f64>>,
+    ifft: Arc<dyn Fft<f64>>,
+    scratch: Vec<Complex<f64>>,
+    spectrum: Vec<Complex<f64>>,
+    partitions: Vec<Vec<Complex<f64>>>,
+    // frequency-domain delay line, the newest input spectrum first
+    spectra: VecDeque<Vec<Complex<f64>>>,
+    first_partition: usize,
+    input: Vec<f64>,
+    output: Vec<f64>,
+    head: Vec<f64>,
+    history: Vec<f64>,
+    position: usize,
+}
+
+impl Convolver {
+    fn new(planner: &mut FftPlanner<f64>, response: &[f64], block: usize, zero_latency: bool) -> Self {
+        let size = 2 * block;
+        let fft = planner.plan_fft_forward(size);
+        let ifft = planner.plan_fft_inverse(size);
+        let scratch_len = fft.get_inplace_scratch_len().max(ifft.get_inplace_scratch_len());
+
+        let mut partitions = Vec::new();
+        for part in response.chunks(block) {
+            let mut spectrum = vec![Complex::new(0.0, 0.0); size];
+            for (i, x) in part.iter().enumerate() {
+                spectrum[i].re = *x;
+            }
+            fft.process(&mut spectrum);
+            partitions.push(spectrum);
+        }
+        if partitions.is_empty() {
+            partitions.push(vec![Complex::new(0.0, 0.0); size]);
+        }
+
+        let first_partition = if zero_latency { 1 } else { 0 };
+        let head = if zero_latency {
+            let mut head: Vec<f64> = response.iter().take(block).copied().collect();
+            head.resize(block, 0.0);
+            head
+        } else {
+            Vec::new()
+        };
+        let depth = partitions.len().saturating_sub(first_partition).max(1);
+
+        Self {
+            block,
+            fft,
+            ifft,
+            scratch: vec![Complex::new(0.0, 0.0); scratch_len],
+            spectrum: vec![Complex::new(0.0, 0.0); size],
+            partitions,
+            spectra: VecDeque::from(vec![vec![Complex::new(0.0, 0.0); size]; depth]),
+            first_partition,
+            input: vec![0.0; size],
+            output: vec![0.0; block],
+            history: vec![0.0; head.len()],
+            head,
+            position: 0,
+        }
+    }
+
+    fn process_sample(&mut self, x: f64) -> f64 {
+        self.input[self.block + self.position] = x;
+        let mut y = self.output[self.position];
+
+        if !self.head.is_empty() {
+            // the history is as long as a block, so position doubles as its write index
+            let n = self.history.len();
+            let now = self.position;
+            self.history[now] = x;
+            for (k, h) in self.head.iter().enumerate() {
+                y += h * self.history[(now + n - k) % n];
+            }
+        }
+
+        self.position += 1;
+        if self.position == self.block {
+            self.process_block();
+        }
+        y
+    }
+
+    fn process_block(&mut self) {
+        // spectrum of the last two blocks of input
+        let mut newest = self.spectra.pop_back().unwrap();
+        for (bin, x) in newest.iter_mut().zip(self.input.iter()) {
+            *bin = Complex::new(*x, 0.0);
+        }
+        self.fft.process_with_scratch(&mut newest, &mut self.scratch);
+        self.spectra.push_front(newest);
+
+        self.spectrum.iter_mut().for_each(|bin| *bin = Complex::new(0.0, 0.0));
+        for (spectrum, partition) in self.spectra.iter().zip(self.partitions.iter().skip(self.first_partition)) {
+            for ((acc, x), h) in self.spectrum.iter_mut().zip(spectrum.iter()).zip(partition.iter()) {
+                *acc += x * h;
+            }
+        }
+        self.ifft.process_with_scratch(&mut self.spectrum, &mut self.scratch);
+
+        // overlap-save, only the second half is free of circular wrap-around
+        let scale = 1.0 / (2 * self.block) as f64;
+        for (y, bin) in self.output.iter_mut().zip(self.spectrum[self.block..].iter()) {
+            *y = bin.re * scale;
+        }
+
+        self.input.copy_within(self.block.., 0);
+        self.position = 0;
+    }
+}
+
+#[derive(Clone)]
+pub struct ConvolutionReverb {
+    // (input channel, output channel, convolver)
+    routes: Vec<(usize, usize, Convolver)>,
+    pre_delay: [Delay; 2],
+    pre_delay_samples: usize,
+    latency: usize,
+}
+
+impl ConvolutionReverb {
+    // block_size is rounded up to a power of two
+    pub fn new(sample_rate: f64, response: &ImpulseResponse, block_size: usize, zero_latency: bool, pre_delay_ms: f64) -> Self {
+        let response = response.clone().resample(sample_rate);
+        let block = block_size.max(1).next_power_of_two();
+        let mut planner = FftPlanner::new();
+
+        let mut convolver = |channel: usize| Convolver::new(&mut planner, &response.channels[channel], block, zero_latency);
+        let routes = match response.channels.len() {
+            0 => Vec::new(),
+            1 => vec![(0, 0, convolver(0)), (1, 1, convolver(0))],
+            4 => vec![(0, 0, convolver(0)), (0, 1, convolver(1)), (1, 0, convolver(2)), (1, 1, convolver(3))],
+            channels => {
+                if channels != 2 {
+                    println!("Impulse response has {} channels. Using the first two", channels);
+                }
+                vec![(0, 0, convolver(0)), (1, 1, convolver(1))]
+            },
+        };
+
+        let mut pre_delay_samples = (pre_delay_ms.max(0.0) / 1000.0 * sample_rate) as usize;
+        if pre_delay_samples > MAX_DELAY {
+            println!("Pre-delay for Convolution Reverb too high. Setting to {} ms", MAX_DELAY as f64 * 1000.0 / sample_rate);
+            pre_delay_samples = MAX_DELAY;
+        }
+
+        Self {
+            routes,
+            pre_delay: [Delay::new(pre_delay_samples, 0.0), Delay::new(pre_delay_samples, 0.0)],
+            pre_delay_samples,
+            latency: if zero_latency { 0 } else { block },
+        }
+    }
+
+    pub fn process_sample(&mut self, left: f64, right: f64) -> (f64, f64) {
+        let mut input = [left, right];
+        if self.pre_delay_samples > 0 {
+            // our delay line is one sample longer than the delay it is given
+            for (x, delay) in input.iter_mut().zip(self.pre_delay.iter_mut()) {
+                *x = delay.process_sample(*x, self.pre_delay_samples - 1);
+            }
+        }
+
+        let mut output = [0.0, 0.0];
+        for (from, to, convolver) in self.routes.iter_mut() {
+            output[*to] += convolver.process_sample(input[*from]);
+        }
+        (output[0], output[1])
+    }
+}
+
+impl AudioNode for ConvolutionReverb {
+    const ID: u64 = 9988;
+    type Sample = f64;
+    type Inputs = typenum::U2;
+    type Outputs = typenum::U2;
+    type Setting = f64;
+
+    fn reset(&mut self) {
+    }
+
+    fn tick(
+        &mut self,
+        input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
+    ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
+        let (left, right) = self.process_sample(input[0], input[1]);
+        [left, right].into()
+    }
+
+    fn latency(&mut self) -> Option<f64> {
+        Some(self.latency as f64)
+    }
+}
+
+/// created a new stereo convolution reverb where:
+///- path is a wav file with 1, 2 or 4 (true stereo) channels
+///- trim_db cuts leading and trailing samples quieter than this, relative to the peak
+///- block_size is the fft partition size in samples
+///- zero_latency convolves the first partition directly instead of adding a block of latency
+///- pre_delay is in ms
+pub fn my_convolution_reverb<P: AsRef<Path>>(sample_rate: f64, path: P, trim_db: f64, block_size: usize, zero_latency: bool, pre_delay_ms: f64) -> anyhow::Result<An<ConvolutionReverb>> {
+    let response = ImpulseResponse::load(path)?.trim(trim_db, None).normalize();
+    Ok(An(ConvolutionReverb::new(sample_rate, &response, block_size, zero_latency, pre_delay_ms)))
+}
+
+/// same as my_convolution_reverb, for mono sources
+pub fn my_mono_convolution_reverb<P: AsRef<Path>>(sample_rate: f64, path: P, trim_db: f64, block_size: usize, zero_latency: bool, pre_delay_ms: f64) -> anyhow::Result<An<impl AudioNode<Sample = f64, Inputs = U1, Outputs = U2>>> {
+    Ok(split::<U2, f64>() >> my_convolution_reverb(sample_rate, path, trim_db, block_size, zero_latency, pre_delay_ms)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = 64;
+
+    // a response a bit over four partitions long
+    fn response() -> Vec<f64> {
+        (0..300).map(|i| (i as f64 * 0.37).sin() * 0.99f64.powi(i)).collect()
+    }
+
+    fn convolve(input: &[f64], response: &[f64], zero_latency: bool) -> Vec<f64> {
+        let mut convolver = Convolver::new(&mut FftPlanner::new(), response, BLOCK, zero_latency);
+        input.iter().map(|x| convolver.process_sample(*x)).collect()
+    }
+
+    fn direct(input: &[f64], response: &[f64]) -> Vec<f64> {
+        (0..input.len())
+            .map(|n| (0..=n.min(response.len() - 1)).map(|k| response[k] * input[n - k]).sum())
+            .collect()
+    }
+
+    #[test]
+    fn impulse_returns_the_response() {
+        let response = response();
+        let impulse: Vec<f64> = (0..600).map(|i| if i == 0 { 1.0 } else { 0.0 }).collect();
+        for zero_latency in [false, true] {
+            let latency = if zero_latency { 0 } else { BLOCK };
+            let output = convolve(&impulse, &response, zero_latency);
+            for (i, y) in output.iter().enumerate() {
+                let expected = i.checked_sub(latency).and_then(|i| response.get(i)).copied().unwrap_or(0.0);
+                assert!((y - expected).abs() < 1e-9, "sample {}: {} against {}", i, y, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn matches_direct_convolution() {
+        let response = response();
+        let input: Vec<f64> = (0..2000).map(|i| (i as f64 * 0.05).sin() + if i % 97 == 0 { 0.5 } else { 0.0 }).collect();
+        let expected = direct(&input, &response);
+
+        let output = convolve(&input, &response, false);
+        for (y, expected) in output[BLOCK..].iter().zip(expected.iter()) {
+            assert!((y - expected).abs() < 1e-9, "{} against {}", y, expected);
+        }
+        let output = convolve(&input, &response, true);
+        for (y, expected) in output.iter().zip(expected.iter()) {
+            assert!((y - expected).abs() < 1e-9, "{} against {}", y, expected);
+        }
+    }
+
+    #[test]
+    fn zero_latency_head_adds_no_delay() {
+        let response = ImpulseResponse::new(44100.0, vec![response()]);
+        let mut reverb = ConvolutionReverb::new(44100.0, &response, BLOCK, true, 0.0);
+        assert_eq!(reverb.latency(), Some(0.0));
+        let (left, right) = reverb.process_sample(1.0, 1.0);
+        assert_eq!((left, right), (response.channels[0][0], response.channels[0][0]));
+
+        let mut delayed = ConvolutionReverb::new(44100.0, &response, BLOCK, false, 0.0);
+        assert_eq!(delayed.latency(), Some(BLOCK as f64));
+    }
+}
@@ -2,7 +2,9 @@ use circular_buffer::CircularBuffer;
 use fundsp::prelude::{AudioNode, An};
 use numeric_array::typenum;
 
-const MAX_DELAY: usize = 3 * 44100; // 3 seconds at 44100Hz
+use crate::bbd::{Bbd, DelayEngine};
+
+pub const MAX_DELAY: usize = 3 * 44100; // 3 seconds at 44100Hz
 
 #[derive(Clone)]
 pub struct Delay {
@@ -10,6 +12,7 @@ pub struct Delay {
     y_buffer: Box<CircularBuffer::<MAX_DELAY, f64>>,
     delay: usize,
     feedback: f64,
+    bbd: Option<Bbd>,
 }
 
 impl Delay {
@@ -39,13 +42,23 @@ impl Delay {
             y_buffer: buffer2,
             delay,
             feedback: feedback,
+            bbd: None,
         }
     }
 
+    // switches between the pristine digital line and the bbd emulation
+    pub fn set_engine(&mut self, sample_rate: f64, engine: DelayEngine, seed: u64) {
+        self.bbd = match engine {
+            DelayEngine::DIGITAL => None,
+            DelayEngine::BBD(stages) => Some(Bbd::new(sample_rate, stages, seed)),
+        };
+    }
+
     // y(n) = x(n - delay) + fb * y(n - delay)
     pub fn process_sample(&mut self, x: f64, delay: usize) -> f64 {
+        let (x, x_delayed) = self.through_engine(x, *self.x_buffer.get(delay).unwrap(), delay as f64);
         let y = 
-        self.x_buffer.get(delay).unwrap() 
+        x_delayed
         + self.feedback * self.y_buffer.get(delay).unwrap();
 
         self.x_buffer.push_front(x);
@@ -53,6 +66,44 @@ impl Delay {
 
         y
     }
+
+    // same as process_sample, but the delay is read with linear interpolation
+    // between the two nearest samples, so it can be modulated smoothly
+    pub fn process_sample_fractional(&mut self, x: f64, delay: f64) -> f64 {
+        let delay = delay.clamp(0.0, (MAX_DELAY - 2) as f64);
+        let index = delay.floor() as usize;
+        let frac = delay - index as f64;
+
+        let x_delayed = lerp(*self.x_buffer.get(index).unwrap(), *self.x_buffer.get(index + 1).unwrap(), frac);
+        let y_delayed = lerp(*self.y_buffer.get(index).unwrap(), *self.y_buffer.get(index + 1).unwrap(), frac);
+        let (x, x_delayed) = self.through_engine(x, x_delayed, delay);
+        let y = x_delayed + self.feedback * y_delayed;
+
+        self.x_buffer.push_front(x);
+        self.y_buffer.push_front(y);
+
+        y
+    }
+
+    // reads the input history, tap(0) is the last sample that went in
+    pub fn tap(&self, index: usize) -> f64 {
+        *self.x_buffer.get(index.min(MAX_DELAY - 1)).unwrap()
+    }
+
+    // runs the incoming and the delayed sample through the bbd, if there is one
+    fn through_engine(&mut self, x: f64, x_delayed: f64, delay: f64) -> (f64, f64) {
+        match &mut self.bbd {
+            Some(bbd) => {
+                bbd.set_delay(delay);
+                (bbd.encode(x), bbd.decode(x_delayed))
+            },
+            None => (x, x_delayed),
+        }
+    }
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t
 }
 
 impl AudioNode for Delay {
@@ -82,4 +133,10 @@ pub fn my_delay(sr: f64, delay_ms: f64, feedback: f64) -> An<Delay> {
     let delay_samples = ((delay_ms as f64 / 1000.0) * sr) as usize;
     println!("delay samples: {}", delay_samples);
     An(Delay::new(delay_samples, feedback))
-}
\ No newline at end of file
+}
+pub fn my_bbd_delay(sr: f64, delay_ms: f64, feedback: f64, stages: usize) -> An<Delay> {
+    let delay_samples = ((delay_ms / 1000.0) * sr) as usize;
+    let mut delay = Delay::new(delay_samples, feedback);
+    delay.set_engine(sr, DelayEngine::BBD(stages), 0);
+    An(delay)
+}
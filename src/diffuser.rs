@@ -0,0 +1,240 @@
+use circular_buffer::CircularBuffer;
+use fundsp::{audionode::AudioNode, prelude::An};
+use numeric_array::typenum::{self};
+
+use crate::lfo::{Lfo, LfoShape};
+
+const MAX_INDEX: usize = 41100;
+
+// schroeder allpass in its one-delay-line form, with optional allpasses nested inside
+// its delay path and an optional modulated delay:
+// s(n) = inner(w(n - delay)), w(n) = x(n) + g * s(n), y(n) = s(n) - g * w(n)
+// any allpass in the delay path keeps the whole thing an allpass
+#[derive(Clone)]
+pub struct NestedAllPass {
+    buffer: Box<CircularBuffer::<MAX_INDEX, f64>>,
+    // in samples, unlike our other lines this is the exact delay
+    delay: f64,
+    gain: f64,
+    // run in series on the delayed signal
+    inner: Vec<NestedAllPass>,
+    // (depth in samples, lfo)
+    modulation: Option<(f64, Lfo)>,
+}
+
+impl NestedAllPass {
+    pub fn new(delay: usize, gain: f64) -> Self {
+        let mut buffer = CircularBuffer::<MAX_INDEX, f64>::boxed();
+        for _ in 0..MAX_INDEX {
+            buffer.push_back(0.0);
+        }
+
+        Self {
+            buffer,
+            delay: delay.clamp(1, MAX_INDEX - 2) as f64,
+            gain: gain.clamp(-0.99, 0.99),
+            inner: Vec::new(),
+            modulation: None,
+        }
+    }
+
+    // nests another allpass in the delay path, after the ones already there
+    pub fn with_inner(mut self, inner: NestedAllPass) -> Self {
+        self.inner.push(inner);
+        self
+    }
+
+    // the delay wanders by depth_ms around its length, rate is in Hz and phase in [0, 1)
+    pub fn with_modulation(mut self, sample_rate: f64, depth_ms: f64, rate: f64, phase: f64) -> Self {
+        // keep at least one sample of delay
+        let depth = (depth_ms.max(0.0) / 1000.0 * sample_rate).min(self.delay - 1.0);
+        self.modulation = Some((depth, Lfo::new(sample_rate, rate, LfoShape::SINE, phase)));
+        self
+    }
+
+    // the delay of this allpass and every allpass nested in it
+    pub fn total_delay(&self) -> f64 {
+        self.delay + self.inner.iter().map(|inner| inner.total_delay()).sum::<f64>()
+    }
+
+    pub fn process_sample(&mut self, x: f64) -> f64 {
+        let delay = match &mut self.modulation {
+            Some((depth, lfo)) => self.delay + *depth * lfo.tick(),
+            None => self.delay,
+        };
+        let mut s = self.read(delay);
+        for inner in self.inner.iter_mut() {
+            s = inner.process_sample(s);
+        }
+
+        let w = x + self.gain * s;
+        self.buffer.push_front(w);
+        s - self.gain * w
+    }
+
+    // get(0) is the last sample that went in, one sample ago
+    fn read(&self, delay: f64) -> f64 {
+        let position = (delay - 1.0).clamp(0.0, (MAX_INDEX - 2) as f64);
+        let index = position.floor() as usize;
+        let frac = position - index as f64;
+        self.buffer.get(index).unwrap() * (1.0 - frac) + self.buffer.get(index + 1).unwrap() * frac
+    }
+}
+
+impl AudioNode for NestedAllPass {
+    const ID: u64 = 9981;
+    type Sample = f64;
+    type Inputs = typenum::U1;
+    type Outputs = typenum::U1;
+    type Setting = f64;
+
+    fn reset(&mut self) {
+    }
+
+    fn tick(
+        &mut self,
+        input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
+    ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
+        let y = self.process_sample(input[0]);
+        [y].into()
+    }
+}
+
+// count lengths spread geometrically from min to max, each moved up to the next unused prime
+// so no two of them share a factor and their echoes never line up
+pub fn coprime_lengths(count: usize, min: usize, max: usize) -> Vec<usize> {
+    let min = min.max(2);
+    let max = max.max(min);
+    let mut lengths: Vec<usize> = Vec::with_capacity(count);
+    for i in 0..count {
+        let t = if count > 1 { i as f64 / (count - 1) as f64 } else { 0.0 };
+        let mut length = (min as f64 * (max as f64 / min as f64).powf(t)).round() as usize;
+        while !is_prime(length) || lengths.contains(&length) {
+            length += 1;
+        }
+        lengths.push(length);
+    }
+    lengths
+}
+
+fn is_prime(n: usize) -> bool {
+    n >= 2 && (2..).take_while(|d| d * d <= n).all(|d| !n.is_multiple_of(d))
+}
+
+// allpasses in series, the usual input diffusion of plates and fdns
+#[derive(Clone)]
+pub struct Diffuser {
+    stages: Vec<NestedAllPass>,
+}
+
+impl Diffuser {
+    pub fn new(stages: Vec<NestedAllPass>) -> Self {
+        Self {
+            stages,
+        }
+    }
+
+    // count allpasses with co-prime lengths between min_ms and max_ms, longest last
+    pub fn coprime(sample_rate: f64, count: usize, min_ms: f64, max_ms: f64, gain: f64) -> Self {
+        let samples = |ms: f64| (ms.max(0.0) / 1000.0 * sample_rate) as usize;
+        let stages = coprime_lengths(count, samples(min_ms), samples(max_ms)).iter()
+            .map(|length| NestedAllPass::new(*length, gain))
+            .collect();
+        Self::new(stages)
+    }
+
+    // same lengths as coprime, but every second allpass is nested inside the one before it
+    pub fn nested(sample_rate: f64, count: usize, min_ms: f64, max_ms: f64, gain: f64) -> Self {
+        let samples = |ms: f64| (ms.max(0.0) / 1000.0 * sample_rate) as usize;
+        let lengths = coprime_lengths(count, samples(min_ms), samples(max_ms));
+        let stages = lengths.chunks(2)
+            .map(|pair| match pair {
+                [inner, outer] => NestedAllPass::new(*outer, gain).with_inner(NestedAllPass::new(*inner, gain)),
+                _ => NestedAllPass::new(pair[0], gain),
+            })
+            .collect();
+        Self::new(stages)
+    }
+
+    // modulates every stage with its own phase and a slightly different rate
+    pub fn with_modulation(mut self, sample_rate: f64, depth_ms: f64, rate: f64) -> Self {
+        let count = self.stages.len();
+        self.stages = self.stages.into_iter().enumerate()
+            .map(|(i, stage)| {
+                let spread = i as f64 / count as f64;
+                stage.with_modulation(sample_rate, depth_ms, rate * (0.8 + 0.4 * spread), spread)
+            })
+            .collect();
+        self
+    }
+
+    pub fn process_sample(&mut self, x: f64) -> f64 {
+        self.stages.iter_mut().fold(x, |y, stage| stage.process_sample(y))
+    }
+}
+
+impl AudioNode for Diffuser {
+    const ID: u64 = 9980;
+    type Sample = f64;
+    type Inputs = typenum::U1;
+    type Outputs = typenum::U1;
+    type Setting = f64;
+
+    fn reset(&mut self) {
+    }
+
+    fn tick(
+        &mut self,
+        input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
+    ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
+        let y = self.process_sample(input[0]);
+        [y].into()
+    }
+}
+
+/// created a new series allpass diffuser where:
+///- count allpasses get co-prime lengths between min and max, in ms
+///- gain is the allpass coefficient, 0.5 to 0.75 is typical
+///- nested puts every second allpass inside the one before it
+///- mod_depth is in ms, 0.0 turns the modulation off
+///- mod_rate is in Hz
+#[allow(clippy::too_many_arguments)]
+pub fn my_diffuser(sample_rate: f64, count: usize, min_ms: f64, max_ms: f64, gain: f64, nested: bool, mod_depth_ms: f64, mod_rate: f64) -> An<Diffuser> {
+    let diffuser = if nested {
+        Diffuser::nested(sample_rate, count, min_ms, max_ms, gain)
+    } else {
+        Diffuser::coprime(sample_rate, count, min_ms, max_ms, gain)
+    };
+    An(if mod_depth_ms > 0.0 { diffuser.with_modulation(sample_rate, mod_depth_ms, mod_rate) } else { diffuser })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn energy(diffuser: &mut Diffuser) -> f64 {
+        (0..200000).map(|i| diffuser.process_sample(if i == 0 { 1.0 } else { 0.0 }).powi(2)).sum()
+    }
+
+    #[test]
+    fn lengths_are_coprime() {
+        let lengths = coprime_lengths(8, 100, 2000);
+        for (i, a) in lengths.iter().enumerate() {
+            for b in lengths[i + 1..].iter() {
+                let (mut x, mut y) = (*a, *b);
+                while y != 0 {
+                    (x, y) = (y, x % y);
+                }
+                assert_eq!(x, 1, "{} and {} share a factor", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn nested_diffusers_keep_the_energy() {
+        // an allpass passes all of the impulse's energy through, nested or not
+        let sample_rate = 44100.0;
+        assert!((energy(&mut Diffuser::coprime(sample_rate, 4, 5.0, 20.0, 0.7)) - 1.0).abs() < 1e-6);
+        assert!((energy(&mut Diffuser::nested(sample_rate, 4, 5.0, 20.0, 0.7)) - 1.0).abs() < 1e-6);
+    }
+}
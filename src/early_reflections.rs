@@ -0,0 +1,145 @@
+use crate::delay::Delay;
+
+const SPEED_OF_SOUND: f64 = 343.0;
+const MAX_ORDER: i32 = 2;
+
+#[derive(Clone, Copy)]
+pub enum RoomPreset {
+    SmallRoom,
+    MediumRoom,
+    Chamber,
+    Hall,
+}
+
+// a shoebox room, sizes and positions are in meters as (x, y, z)
+// where x points from the left wall to the right one
+#[derive(Clone, Copy)]
+pub struct RoomGeometry {
+    pub size: (f64, f64, f64),
+    pub source: (f64, f64, f64),
+    pub listener: (f64, f64, f64),
+    // fraction of the amplitude that survives a wall bounce, in [0, 1]
+    pub reflectivity: f64,
+}
+
+impl RoomGeometry {
+    pub fn from_preset(preset: RoomPreset) -> Self {
+        match preset {
+            RoomPreset::SmallRoom => Self {
+                size: (4.0, 5.0, 2.7),
+                source: (2.5, 3.5, 1.5),
+                listener: (1.8, 1.5, 1.7),
+                reflectivity: 0.7,
+            },
+            RoomPreset::MediumRoom => Self {
+                size: (8.0, 11.0, 3.5),
+                source: (3.0, 7.5, 1.5),
+                listener: (4.5, 3.0, 1.7),
+                reflectivity: 0.75,
+            },
+            RoomPreset::Chamber => Self {
+                size: (6.0, 9.0, 5.0),
+                source: (4.0, 6.0, 2.0),
+                listener: (2.0, 2.5, 1.7),
+                reflectivity: 0.85,
+            },
+            RoomPreset::Hall => Self {
+                size: (22.0, 40.0, 14.0),
+                source: (11.0, 30.0, 2.0),
+                listener: (9.0, 12.0, 1.7),
+                reflectivity: 0.8,
+            },
+        }
+    }
+
+    // image-source method up to the second order. every tap is (delay in seconds
+    // relative to the direct sound, gain relative to the direct sound, pan in [-1, 1])
+    pub fn reflections(&self) -> Vec<(f64, f64, f64)> {
+        let direct = distance(self.source, self.listener);
+
+        let mut taps = Vec::new();
+        for i in -MAX_ORDER..=MAX_ORDER {
+            for j in -MAX_ORDER..=MAX_ORDER {
+                for k in -MAX_ORDER..=MAX_ORDER {
+                    let order = i.abs() + j.abs() + k.abs();
+                    if order == 0 || order > MAX_ORDER {
+                        continue;
+                    }
+                    let image = (
+                        image_coordinate(self.source.0, self.size.0, i),
+                        image_coordinate(self.source.1, self.size.1, j),
+                        image_coordinate(self.source.2, self.size.2, k),
+                    );
+                    let d = distance(image, self.listener);
+                    let delay = (d - direct) / SPEED_OF_SOUND;
+                    let gain = self.reflectivity.powi(order) * direct / d;
+                    let pan = ((image.0 - self.listener.0) / d).clamp(-1.0, 1.0);
+                    taps.push((delay, gain, pan));
+                }
+            }
+        }
+        taps
+    }
+}
+
+// position of the n-th mirror image of x in a room of the given length
+fn image_coordinate(x: f64, length: f64, n: i32) -> f64 {
+    if n % 2 == 0 {
+        n as f64 * length + x
+    } else {
+        n as f64 * length + (length - x)
+    }
+}
+
+fn distance(a: (f64, f64, f64), b: (f64, f64, f64)) -> f64 {
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2) + (a.2 - b.2).powi(2)).sqrt()
+}
+
+#[derive(Clone)]
+pub struct EarlyReflections {
+    line: Delay,
+    // (delay in samples, left gain, right gain)
+    taps: Vec<(usize, f64, f64)>,
+}
+
+impl EarlyReflections {
+    pub fn new(sample_rate: f64, geometry: RoomGeometry) -> Self {
+        let reflections = geometry.reflections();
+
+        // keep the overall level close to the direct sound, no matter how many taps there are
+        let energy: f64 = reflections.iter().map(|(_, gain, _)| gain * gain).sum();
+        let scale = if energy > 0.0 { 1.0 / energy.sqrt() } else { 0.0 };
+
+        let taps = reflections.iter()
+            .map(|(delay, gain, pan)| {
+                let samples = (delay * sample_rate).round() as usize;
+                // balance pan law, like the chorus voices
+                let left = (1.0 - pan).min(1.0);
+                let right = (1.0 + pan).min(1.0);
+                (samples, gain * scale * left, gain * scale * right)
+            })
+            .collect();
+
+        Self {
+            line: Delay::new(0, 0.0),
+            taps,
+        }
+    }
+
+    pub fn new_preset(sample_rate: f64, preset: RoomPreset) -> Self {
+        Self::new(sample_rate, RoomGeometry::from_preset(preset))
+    }
+
+    pub fn process_sample(&mut self, x: f64) -> (f64, f64) {
+        self.line.process_sample(x, 0);
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (delay, gain_l, gain_r) in self.taps.iter() {
+            let y = self.line.tap(*delay);
+            left += gain_l * y;
+            right += gain_r * y;
+        }
+        (left, right)
+    }
+}
@@ -1,18 +1,22 @@
 use fundsp::{prelude::{AudioNode, An}, hacker::U1};
 
-use crate::{waveshaper::{my_waveshaper, ShapeType}, filter::{my_second_order_lpf, my_second_order_hpf, my_low_shelf, my_high_shelf}};
+use crate::{waveshaper::{Waveshaper, ShapeType}, filter::{my_second_order_lpf, my_second_order_hpf, my_low_shelf, my_high_shelf}};
+use crate::oversampling::{DEFAULT_OVERSAMPLING, oversample};
+use crate::triode::{TriodeSettings, my_triode};
+use crate::tone_stack::{ToneStack, ToneStackType, my_tone_stack};
 
 
+// the four shapers run oversampled as one block, the filters after them at the base rate
 pub fn triode_class_a(sample_rate: f64, gain: f64, saturation: f64, low_shelf_gain: f64) -> An<impl AudioNode<Sample = f64, Inputs = U1, Outputs = U1>> {
-    my_waveshaper(ShapeType::TANH, gain, 0.9, Some(saturation))
-    >> my_waveshaper(ShapeType::TANH, gain, 0.9, Some(saturation))
-    >> my_waveshaper(ShapeType::TANH, gain, 0.9, Some(saturation))
-    >> my_waveshaper(ShapeType::TANH, gain, 0.9, Some
-    (saturation)) * -1.0
+    let shaper = || An(Waveshaper::new(ShapeType::TANH, saturation, gain, 0.9));
+    oversample(DEFAULT_OVERSAMPLING,
+        shaper()
+        >> shaper()
+        >> shaper()
+        >> (shaper() * -1.0))
     >> my_second_order_hpf(sample_rate, 100.0, 1.0)
-    >> my_low_shelf(sample_rate, 500.0, low_shelf_gain) * 0.7
+    >> (my_low_shelf(sample_rate, 500.0, low_shelf_gain) * 0.7)
 }
-
 pub fn class_a_tube_pre(sample_rate: f64, gain: f64, saturation: f64, low_shelf_gain: f64, high_shelf_gain: f64) -> An<impl AudioNode<Sample = f64, Inputs = U1, Outputs = U1>> {
     triode_class_a(sample_rate, gain, saturation, 0.0)
     >> triode_class_a(sample_rate, gain, saturation, 0.0)
@@ -20,4 +24,25 @@ pub fn class_a_tube_pre(sample_rate: f64, gain: f64, saturation: f64, low_shelf_
     >> triode_class_a(sample_rate, gain, saturation, 0.0)
     >> my_low_shelf(sample_rate, 500.0, low_shelf_gain)
     >> my_high_shelf(sample_rate, 6000.0, high_shelf_gain)
-}
\ No newline at end of file
+}
+
+// same filters as triode_class_a around one stateful triode, see Triode
+pub fn dynamic_triode_class_a(sample_rate: f64, drive: f64, settings: TriodeSettings, low_shelf_gain: f64) -> An<impl AudioNode<Sample = f64, Inputs = U1, Outputs = U1>> {
+    my_triode(sample_rate, settings, drive)
+    >> my_second_order_hpf(sample_rate, 100.0, 1.0)
+    >> (my_low_shelf(sample_rate, 500.0, low_shelf_gain) * 0.7)
+}
+pub fn dynamic_tube_pre(sample_rate: f64, drive: f64, settings: TriodeSettings, low_shelf_gain: f64, high_shelf_gain: f64) -> An<impl AudioNode<Sample = f64, Inputs = U1, Outputs = U1>> {
+    dynamic_triode_class_a(sample_rate, drive, settings, 0.0)
+    >> dynamic_triode_class_a(sample_rate, drive, settings, 0.0)
+    >> my_low_shelf(sample_rate, 500.0, low_shelf_gain)
+    >> my_high_shelf(sample_rate, 6000.0, high_shelf_gain)
+}
+
+// the preamp into a passive tone stack instead of the shelves, knobs are in [0, 1].
+// the stack loses 5 to 20dB, the makeup gain brings its peak back to unity with the knobs at noon
+pub fn tube_pre_with_tone_stack(sample_rate: f64, gain: f64, saturation: f64, stack_type: ToneStackType, bass: f64, mid: f64, treble: f64) -> An<impl AudioNode<Sample = f64, Inputs = U1, Outputs = U1>> {
+    let makeup = 1.0 / ToneStack::new(sample_rate, stack_type, 0.5, 0.5, 0.5).peak_gain();
+    class_a_tube_pre(sample_rate, gain, saturation, 0.0, 0.0)
+    >> (my_tone_stack(sample_rate, stack_type, bass, mid, treble) * makeup)
+}
@@ -0,0 +1,123 @@
+use fundsp::{audionode::AudioNode, prelude::An};
+use numeric_array::typenum::{self};
+
+// one-pole coefficient that gets within 1/e of the target after time_ms
+fn time_coefficient(sample_rate: f64, time_ms: f64) -> f64 {
+    let samples = time_ms / 1000.0 * sample_rate;
+    if samples > 0.0 {
+        (-1.0 / samples).exp()
+    } else {
+        0.0
+    }
+}
+
+// peak follower with separate attack and release times
+#[derive(Clone)]
+pub struct EnvelopeFollower {
+    attack: f64,
+    release: f64,
+    envelope: f64,
+}
+
+impl EnvelopeFollower {
+    // times are in ms
+    pub fn new(sample_rate: f64, attack_ms: f64, release_ms: f64) -> Self {
+        Self {
+            attack: time_coefficient(sample_rate, attack_ms),
+            release: time_coefficient(sample_rate, release_ms),
+            envelope: 0.0,
+        }
+    }
+
+    pub fn process_sample(&mut self, x: f64) -> f64 {
+        let level = x.abs();
+        let coefficient = if level > self.envelope { self.attack } else { self.release };
+        self.envelope = level + coefficient * (self.envelope - level);
+        self.envelope
+    }
+
+    pub fn value(&self) -> f64 {
+        self.envelope
+    }
+}
+
+// noise gate driven by a key signal. it opens as soon as the key goes over the threshold,
+// stays open for the hold time after the key falls below it and then fades out over the release
+#[derive(Clone)]
+pub struct Gate {
+    detector: EnvelopeFollower,
+    threshold: f64,
+    attack_step: f64,
+    release_step: f64,
+    hold_samples: usize,
+    hold_counter: usize,
+    gain: f64,
+}
+
+impl Gate {
+    // threshold is in dB, times are in ms
+    pub fn new(sample_rate: f64, threshold_db: f64, attack_ms: f64, hold_ms: f64, release_ms: f64) -> Self {
+        let samples = |ms: f64| (ms.max(0.0) / 1000.0 * sample_rate) as usize;
+
+        Self {
+            // fast detector, the hold covers the gaps between peaks
+            detector: EnvelopeFollower::new(sample_rate, 0.1, 5.0),
+            threshold: 10f64.powf(threshold_db / 20.0),
+            attack_step: 1.0 / samples(attack_ms).max(1) as f64,
+            release_step: 1.0 / samples(release_ms).max(1) as f64,
+            hold_samples: samples(hold_ms),
+            hold_counter: 0,
+            gain: 0.0,
+        }
+    }
+
+    // returns the gain to apply for this key sample, in [0, 1]
+    pub fn process_key(&mut self, key: f64) -> f64 {
+        if self.detector.process_sample(key) >= self.threshold {
+            self.hold_counter = self.hold_samples;
+            self.gain = (self.gain + self.attack_step).min(1.0);
+        } else if self.hold_counter > 0 {
+            self.hold_counter -= 1;
+            self.gain = (self.gain + self.attack_step).min(1.0);
+        } else {
+            self.gain = (self.gain - self.release_step).max(0.0);
+        }
+        self.gain
+    }
+
+    pub fn process_sample(&mut self, x: f64, key: f64) -> f64 {
+        x * self.process_key(key)
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.gain > 0.0
+    }
+}
+
+impl AudioNode for Gate {
+    const ID: u64 = 9985;
+    type Sample = f64;
+    // the signal, then the key
+    type Inputs = typenum::U2;
+    type Outputs = typenum::U1;
+    type Setting = f64;
+
+    fn reset(&mut self) {
+    }
+
+    fn tick(
+        &mut self,
+        input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
+    ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
+        let y = self.process_sample(input[0], input[1]);
+        [y].into()
+    }
+}
+
+/// created a new keyed noise gate where:
+///- the first input is gated, the second one is the key
+///- threshold is in dB
+///- attack, hold and release are in ms
+pub fn my_gate(sample_rate: f64, threshold_db: f64, attack_ms: f64, hold_ms: f64, release_ms: f64) -> An<Gate> {
+    An(Gate::new(sample_rate, threshold_db, attack_ms, hold_ms, release_ms))
+}
@@ -0,0 +1,205 @@
+use circular_buffer::CircularBuffer;
+use fundsp::{audionode::AudioNode, prelude::An};
+use numeric_array::typenum::{self};
+
+use crate::filter::{BiquadFilter, low_shelf_coefficients, high_shelf_coefficients};
+use crate::lfo::{Lfo, LfoShape};
+
+const MAX_INDEX: usize = 41100;
+const MAX_LINES: usize = 16;
+
+// mutually prime line lengths in samples at 44.1kHz, smaller networks take every n-th one
+const FDN_DELAYS: [f64; MAX_LINES] = [
+    1031.0, 1327.0, 1523.0, 1801.0,
+    1993.0, 2203.0, 2371.0, 2591.0,
+    2789.0, 2999.0, 3209.0, 3413.0,
+    3607.0, 3803.0, 4001.0, 4201.0,
+];
+
+// crossover frequencies between the low/mid and mid/high decay bands
+const LOW_CROSSOVER: f64 = 500.0;
+const HIGH_CROSSOVER: f64 = 4000.0;
+
+#[derive(Clone, Copy)]
+pub enum FdnMatrix {
+    HADAMARD,
+    HOUSEHOLDER,
+}
+
+#[derive(Clone)]
+struct FdnLine {
+    buffer: Box<CircularBuffer::<MAX_INDEX, f64>>,
+    delay: f64,
+    gain: f64,
+    low_shelf: BiquadFilter,
+    high_shelf: BiquadFilter,
+    lfo: Lfo,
+}
+
+impl FdnLine {
+    fn new(sample_rate: f64, delay: f64, rt60: (f64, f64, f64), lfo: Lfo) -> Self {
+        let mut buffer = CircularBuffer::<MAX_INDEX, f64>::boxed();
+        for _ in 0..MAX_INDEX {
+            buffer.push_back(0.0);
+        }
+
+        // attenuation per pass so the line decays by 60dB in rt60 seconds
+        let (low, mid, high) = rt60;
+        let g = |rt60: f64| 10f64.powf(-3.0 * delay / (rt60.max(0.01) * sample_rate));
+        let (g_low, g_mid, g_high) = (g(low), g(mid), g(high));
+
+        // the mid gain is applied directly, the shelves bend the low and high bands towards their own decay
+        let low_db = 20.0 * (g_low / g_mid).log10();
+        let high_db = 20.0 * (g_high / g_mid).log10();
+
+        Self {
+            buffer,
+            delay,
+            gain: g_mid,
+            low_shelf: BiquadFilter::new(low_shelf_coefficients(sample_rate, LOW_CROSSOVER, low_db)),
+            high_shelf: BiquadFilter::new(high_shelf_coefficients(sample_rate, HIGH_CROSSOVER, high_db)),
+            lfo,
+        }
+    }
+
+    fn read(&mut self, depth: f64) -> f64 {
+        let delay = (self.delay + depth * self.lfo.tick()).clamp(1.0, (MAX_INDEX - 2) as f64);
+        let index = delay.floor() as usize;
+        let frac = delay - index as f64;
+        let a = *self.buffer.get(index).unwrap();
+        let b = *self.buffer.get(index + 1).unwrap();
+        let y = a + (b - a) * frac;
+
+        self.high_shelf.process_sample(self.low_shelf.process_sample(self.gain * y))
+    }
+}
+
+#[derive(Clone)]
+pub struct FdnReverb {
+    lines: Vec<FdnLine>,
+    matrix: FdnMatrix,
+    mod_depth: f64,
+    mixed: Vec<f64>,
+}
+
+impl FdnReverb {
+    /// lines is 4, 8 or 16, rt60 is the (low, mid, high) decay time in seconds,
+    /// mod_depth is in ms and mod_rate in Hz
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(sample_rate: f64, lines: usize, matrix: FdnMatrix, rt60_low: f64, rt60_mid: f64, rt60_high: f64, mod_depth: f64, mod_rate: f64) -> Self {
+        let count = match lines {
+            0..=4 => 4,
+            5..=8 => 8,
+            _ => 16,
+        };
+        if count != lines {
+            println!("FDN supports 4, 8 or 16 lines. Using {}", count);
+        }
+
+        let step = MAX_LINES / count;
+        let mut fdn_lines = Vec::with_capacity(count);
+        for i in 0..count {
+            let delay = (FDN_DELAYS[i * step] * sample_rate / 44100.0).floor();
+            // spread the lfo phases and rates so the lines don't move together
+            let lfo = Lfo::new(sample_rate, mod_rate * (1.0 + 0.1 * i as f64), LfoShape::SINE, i as f64 / count as f64);
+            fdn_lines.push(FdnLine::new(sample_rate, delay, (rt60_low, rt60_mid, rt60_high), lfo));
+        }
+
+        Self {
+            lines: fdn_lines,
+            matrix,
+            mod_depth: mod_depth.max(0.0) * sample_rate / 1000.0,
+            mixed: vec![0.0; count],
+        }
+    }
+
+    pub fn process_sample(&mut self, x: f64) -> (f64, f64) {
+        let n = self.lines.len();
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, line) in self.lines.iter_mut().enumerate() {
+            let y = line.read(self.mod_depth);
+            self.mixed[i] = y;
+            // even lines go left, odd lines go right, with alternating signs to decorrelate
+            let sign = if (i / 2) % 2 == 0 { 1.0 } else { -1.0 };
+            if i % 2 == 0 {
+                left += sign * y;
+            } else {
+                right += sign * y;
+            }
+        }
+
+        match self.matrix {
+            FdnMatrix::HADAMARD => hadamard(&mut self.mixed),
+            FdnMatrix::HOUSEHOLDER => householder(&mut self.mixed),
+        }
+
+        for (i, line) in self.lines.iter_mut().enumerate() {
+            let sign = if i % 2 == 0 { 1.0 } else { -1.0 };
+            line.buffer.push_front(sign * x + self.mixed[i]);
+        }
+
+        let scale = 1.0 / (n as f64 / 2.0).sqrt();
+        (left * scale, right * scale)
+    }
+}
+
+// fast walsh-hadamard transform, normalized so the matrix is orthogonal
+fn hadamard(x: &mut [f64]) {
+    let n = x.len();
+    let mut h = 1;
+    while h < n {
+        for i in (0..n).step_by(h * 2) {
+            for j in i..(i + h) {
+                let a = x[j];
+                let b = x[j + h];
+                x[j] = a + b;
+                x[j + h] = a - b;
+            }
+        }
+        h *= 2;
+    }
+    let scale = 1.0 / (n as f64).sqrt();
+    for v in x.iter_mut() {
+        *v *= scale;
+    }
+}
+
+// I - (2/n) * 1 * 1^T
+fn householder(x: &mut [f64]) {
+    let sum: f64 = x.iter().sum();
+    let k = 2.0 * sum / x.len() as f64;
+    for v in x.iter_mut() {
+        *v -= k;
+    }
+}
+
+impl AudioNode for FdnReverb {
+    const ID: u64 = 9990;
+    type Sample = f64;
+    type Inputs = typenum::U1;
+    type Outputs = typenum::U2;
+    type Setting = f64;
+
+    fn reset(&mut self) {
+    }
+
+    fn tick(
+        &mut self,
+        input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
+    ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
+        let (left, right) = self.process_sample(input[0]);
+        [left, right].into()
+    }
+}
+
+/// created a new stereo feedback delay network reverb where:
+///- lines is 4, 8 or 16
+///- rt60_low, rt60_mid, rt60_high are the decay times in seconds below 500Hz, in between and above 4kHz
+///- mod_depth is in ms
+///- mod_rate is in Hz
+#[allow(clippy::too_many_arguments)]
+pub fn my_fdn_reverb(sample_rate: f64, lines: usize, matrix: FdnMatrix, rt60_low: f64, rt60_mid: f64, rt60_high: f64, mod_depth: f64, mod_rate: f64) -> An<FdnReverb> {
+    An(FdnReverb::new(sample_rate, lines, matrix, rt60_low, rt60_mid, rt60_high, mod_depth, mod_rate))
+}
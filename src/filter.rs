@@ -120,6 +120,92 @@ impl AudioNode for BiquadFilter {
     }
 }
 
+// one-pole lowpass without the zero at nyquist of the bilinear version:
+// y(n) = (1 - g) * x(n) + g * y(n - 1)
+// the usual damping filter in reverb and string feedback loops
+#[derive(Clone, Copy)]
+pub struct OnePole {
+    g: f64,
+    y1: f64,
+}
+
+impl OnePole {
+    // cutoff is the -3dB frequency in Hz
+    pub fn new(sample_rate: f64, cutoff: f64) -> Self {
+        Self::from_coefficient(one_pole_coefficient(sample_rate, cutoff))
+    }
+
+    // g is the pole in [0, 1), 0.0 lets everything through
+    pub fn from_coefficient(g: f64) -> Self {
+        Self {
+            g: g.clamp(0.0, 0.9999),
+            y1: 0.0,
+        }
+    }
+
+    pub fn set_cutoff(&mut self, sample_rate: f64, cutoff: f64) {
+        self.g = one_pole_coefficient(sample_rate, cutoff);
+    }
+
+    pub fn coefficient(&self) -> f64 {
+        self.g
+    }
+
+    pub fn process_sample(&mut self, x: f64) -> f64 {
+        self.y1 = (1.0 - self.g) * x + self.g * self.y1;
+        self.y1
+    }
+
+    pub fn reset(&mut self) {
+        self.y1 = 0.0;
+    }
+}
+
+// pole of a one-pole lowpass with its -3dB point at cutoff
+pub fn one_pole_coefficient(sample_rate: f64, cutoff: f64) -> f64 {
+    let cutoff = cutoff.clamp(0.0, 0.5 * sample_rate);
+    let c = 2.0 - (2.0 * PI * cutoff / sample_rate).cos();
+    (c - (c * c - 1.0).sqrt()).clamp(0.0, 0.9999)
+}
+
+// first-order highpass with its zero exactly at dc:
+// y(n) = x(n) - x(n - 1) + r * y(n - 1)
+// for removing offsets after nonlinear stages, cutoff is usually a few Hz
+#[derive(Clone, Copy)]
+pub struct DcBlocker {
+    cutoff: f64,
+    r: f64,
+    x1: f64,
+    y1: f64,
+}
+
+impl DcBlocker {
+    pub fn new(sample_rate: f64, cutoff: f64) -> Self {
+        let mut blocker = Self {
+            cutoff: cutoff.max(0.0),
+            r: 0.0,
+            x1: 0.0,
+            y1: 0.0,
+        };
+        blocker.set_sample_rate(sample_rate);
+        blocker
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.r = (-2.0 * PI * self.cutoff / sample_rate).exp().clamp(0.0, 0.99999);
+    }
+
+    pub fn process_sample(&mut self, x: f64) -> f64 {
+        self.y1 = x - self.x1 + self.r * self.y1;
+        self.x1 = x;
+        self.y1
+    }
+
+    pub fn reset(&mut self) {
+        self.x1 = 0.0;
+        self.y1 = 0.0;
+    }
+}
 
 pub fn first_order_lpf_coefficients(sample_rate: f64, cutoff: f64) -> BiquadCoefficients {
     let o = 2.0 * PI * cutoff / sample_rate;
@@ -156,7 +242,6 @@ pub fn second_order_lpf_coefficients(sample_rate: f64, cutoff: f64, q: f64) -> B
     let a2 = (0.5 + b - y) / 2.0;
     let b0 = -2.0 * y;
     let b1 = 2.0 * b;
-    println!("a0: {}\na1: {}\na2: {}\nb0: {}\nb1: {}", a0, a1, a2, b0, b1);
     BiquadCoefficients::new(a0, a1, a2, b0, b1, 1.0, 0.0)
 }
 
@@ -0,0 +1,162 @@
+use fundsp::{audionode::AudioNode, prelude::An};
+use numeric_array::typenum::{self};
+
+use crate::delay::{Delay, MAX_DELAY};
+use crate::lfo::{Lfo, LfoShape};
+
+// the delay sweep, the defaults are a slow classic flange
+#[derive(Clone, Copy)]
+pub struct FlangerSettings {
+    pub shape: LfoShape,
+    // in Hz
+    pub rate: f64,
+    // sweep width in ms
+    pub depth_ms: f64,
+    // minimum delay in ms
+    pub manual_ms: f64,
+    // delays the dry path so the wet path can cross it
+    pub through_zero: bool,
+}
+
+impl Default for FlangerSettings {
+    fn default() -> Self {
+        Self {
+            shape: LfoShape::TRIANGLE,
+            rate: 0.25,
+            depth_ms: 2.0,
+            manual_ms: 0.5,
+            through_zero: false,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Flanger {
+    wet_line: Delay,
+    dry_line: Delay,
+    lfo: Lfo,
+    depth_samples: f64,
+    manual_samples: f64,
+    feedback: f64,
+    mix: f64,
+    through_zero: bool,
+    last_wet: f64,
+}
+
+impl Flanger {
+    pub fn new(sample_rate: f64, settings: FlangerSettings, feedback: f64, mix: f64) -> Self {
+        let FlangerSettings { shape, rate, depth_ms, manual_ms, through_zero } = settings;
+
+        // the longest wet delay is manual + depth, or manual + 2 * depth through zero
+        let max_samples = (MAX_DELAY - 2) as f64;
+        let sweep = if through_zero { 2.0 } else { 1.0 };
+        let mut manual_samples = manual_ms.max(0.0) * sample_rate / 1000.0;
+        if manual_samples > max_samples {
+            println!("Manual delay for Flanger too high. Setting to {} ms", max_samples * 1000.0 / sample_rate);
+            manual_samples = max_samples;
+        }
+        let mut depth_samples = depth_ms.max(0.0) * sample_rate / 1000.0;
+        if manual_samples + sweep * depth_samples > max_samples {
+            depth_samples = (max_samples - manual_samples) / sweep;
+            println!("Depth for Flanger too high. Setting to {} ms", depth_samples * 1000.0 / sample_rate);
+        }
+
+        // negative feedback flips the polarity of the recirculated signal
+        let feedback = feedback.clamp(-0.99, 0.99);
+        let mix = mix.clamp(0.0, 1.0);
+
+        Self {
+            wet_line: Delay::new(0, 0.0),
+            dry_line: Delay::new(0, 0.0),
+            lfo: Lfo::new(sample_rate, rate, shape, 0.0),
+            depth_samples,
+            manual_samples,
+            feedback,
+            mix,
+            through_zero,
+            last_wet: 0.0,
+        }
+    }
+
+    pub fn process_sample(&mut self, x: f64) -> f64 {
+        let lfo = self.lfo.tick();
+
+        let (dry, delay) = if self.through_zero {
+            // the dry path sits in the middle of the sweep, so the wet path
+            // passes through it at the lfo zero crossings
+            let middle = self.manual_samples + self.depth_samples;
+            let dry = self.dry_line.process_sample(x, middle.round() as usize);
+            (dry, self.manual_samples + self.depth_samples * (1.0 + lfo))
+        } else {
+            (x, self.manual_samples + self.depth_samples * 0.5 * (1.0 + lfo))
+        };
+
+        let wet = self.wet_line.process_sample_fractional(x + self.feedback * self.last_wet, delay);
+        self.last_wet = wet;
+
+        (1.0 - self.mix) * dry + self.mix * wet
+    }
+}
+
+impl AudioNode for Flanger {
+    const ID: u64 = 9991;
+    type Sample = f64;
+    type Inputs = typenum::U1;
+    type Outputs = typenum::U1;
+    type Setting = f64;
+
+    fn reset(&mut self) {
+        self.wet_line = Delay::new(0, 0.0);
+        self.dry_line = Delay::new(0, 0.0);
+        self.lfo.reset(0.0);
+        self.last_wet = 0.0;
+    }
+
+    fn tick(
+            &mut self,
+            input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
+        ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
+        let x = input[0];
+        let y = self.process_sample(x);
+        [y].into()
+    }
+}
+
+/// created a new flanger effect where:
+///- settings are the lfo shape, rate in Hz, sweep width and minimum delay in ms and through zero mode
+///- feedback is in [-0.99, 0.99], negative values invert the feedback polarity
+///- mix is in [0, 1], 0.5 gives the deepest notches
+pub fn my_flanger(sample_rate: f64, settings: FlangerSettings, feedback: f64, mix: f64) -> An<Flanger> {
+    An(Flanger::new(sample_rate, settings, feedback, mix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn through_zero_crosses_the_dry_path_with_a_manual_offset() {
+        // 2ms manual and 1ms depth at 48kHz put the middle of the sweep at 144 samples
+        let settings = FlangerSettings { shape: LfoShape::SINE, rate: 0.01, depth_ms: 1.0, manual_ms: 2.0, through_zero: true };
+        let mut flanger = Flanger::new(48000.0, settings, 0.0, 0.5);
+        for i in 0..400 {
+            let y = flanger.process_sample((i as f64 * 0.01).sin());
+            // both lines are one sample longer than their delay
+            let expected = if i >= 145 { ((i - 145) as f64 * 0.01).sin() } else { 0.0 };
+            assert!((y - expected).abs() < 0.01, "sample {}: {} against {}", i, y, expected);
+        }
+    }
+
+    #[test]
+    fn long_sweeps_are_clamped_to_the_delay_line() {
+        let settings = FlangerSettings { shape: LfoShape::TRIANGLE, rate: 0.3, depth_ms: 5000.0, manual_ms: 1000.0, through_zero: true };
+        let mut flanger = Flanger::new(44100.0, settings, 0.5, 0.5);
+        let longest = flanger.manual_samples + 2.0 * flanger.depth_samples;
+        assert!(longest <= (MAX_DELAY - 2) as f64, "longest delay was {}", longest);
+        assert!(flanger.manual_samples + flanger.depth_samples < MAX_DELAY as f64);
+        for i in 0..1000 {
+            let y = flanger.process_sample((i as f64 * 0.01).sin());
+            assert!(y.is_finite());
+        }
+    }
+}
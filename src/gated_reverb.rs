@@ -0,0 +1,67 @@
+use fundsp::{audionode::AudioNode, prelude::An};
+use numeric_array::typenum::{self};
+
+use crate::envelope::Gate;
+use crate::reverb::{Reverb, ReverbType};
+use crate::seed::DEFAULT_SEED;
+
+const GATE_ATTACK_MS: f64 = 1.0;
+
+// the reverb tail is cut by a gate keyed from the dry input, so it stays
+// dense while the source plays and stops dead shortly after it
+#[derive(Clone)]
+pub struct GatedReverb {
+    reverb: Reverb,
+    gate: Gate,
+    mix: f64,
+}
+
+impl GatedReverb {
+    // the reverb should be fully wet, the mix is applied here
+    pub fn new(sample_rate: f64, reverb: Reverb, threshold_db: f64, hold_ms: f64, release_ms: f64, mix: f64) -> Self {
+        Self {
+            reverb,
+            gate: Gate::new(sample_rate, threshold_db, GATE_ATTACK_MS, hold_ms, release_ms),
+            mix: mix.clamp(0.0, 1.0),
+        }
+    }
+
+    pub fn process_sample(&mut self, x: f64) -> (f64, f64) {
+        let (left, right) = self.reverb.process_sample_stereo(x);
+        let gain = self.gate.process_key(x) * self.mix;
+        (
+            gain * left + (1.0 - self.mix) * x,
+            gain * right + (1.0 - self.mix) * x,
+        )
+    }
+}
+
+impl AudioNode for GatedReverb {
+    const ID: u64 = 9984;
+    type Sample = f64;
+    type Inputs = typenum::U1;
+    type Outputs = typenum::U2;
+    type Setting = f64;
+
+    fn reset(&mut self) {
+    }
+
+    fn tick(
+        &mut self,
+        input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
+    ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
+        let (left, right) = self.process_sample(input[0]);
+        [left, right].into()
+    }
+}
+
+/// created a new gated reverb where:
+///- decay is in seconds, long decays give the classic dense gated sound
+///- threshold is the gate threshold in dB, keyed from the dry input
+///- hold and release are in ms
+///- mix is in [0, 1], 1.0 is fully wet
+#[allow(clippy::too_many_arguments)]
+pub fn my_gated_reverb(sample_rate: f64, reverb_type: ReverbType, decay: f64, damp: f64, threshold_db: f64, hold_ms: f64, release_ms: f64, mix: f64) -> An<GatedReverb> {
+    let reverb = Reverb::new(sample_rate, decay, reverb_type, damp, DEFAULT_SEED);
+    An(GatedReverb::new(sample_rate, reverb, threshold_db, hold_ms, release_ms, mix))
+}
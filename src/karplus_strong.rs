@@ -0,0 +1,243 @@
+use std::f64::consts::PI;
+
+use fundsp::{audionode::AudioNode, prelude::An, wave::Wave64};
+use numeric_array::typenum::{self};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::comb::{CombFilter, CombType};
+use crate::filter::OnePole;
+use crate::seed::DEFAULT_SEED;
+
+// voices are dropped once they have rung this many decay times
+const RELEASE_MARGIN: f64 = 1.5;
+
+#[derive(Clone)]
+pub enum Excitation {
+    // one period of white noise, the classic pluck
+    Noise,
+    // any burst, played into the string once per note
+    Custom(Vec<f64>),
+}
+
+#[derive(Clone)]
+pub struct StringSettings {
+    pub excitation: Excitation,
+    // where the string is plucked as a fraction of its length, in (0, 0.5]
+    pub pick_position: f64,
+    // seconds for the fundamental to fall by 60dB. the loop gain is stretched per note,
+    // so low and high notes ring for the same time
+    pub decay: f64,
+    // pole of the loop lowpass in [0, 0.9], higher values make the upper partials die out faster
+    pub damping: f64,
+}
+
+impl Default for StringSettings {
+    fn default() -> Self {
+        Self {
+            excitation: Excitation::Noise,
+            pick_position: 0.13,
+            decay: 3.0,
+            damping: 0.3,
+        }
+    }
+}
+
+pub fn midi_to_frequency(note: u8) -> f64 {
+    440.0 * 2f64.powf((note as f64 - 69.0) / 12.0)
+}
+
+#[derive(Clone)]
+struct PluckedString {
+    comb: CombFilter,
+    excitation: Vec<f64>,
+    position: usize,
+    remaining: usize,
+}
+
+impl PluckedString {
+    // velocity is in [0, 1]
+    fn new(sample_rate: f64, frequency: f64, velocity: f64, settings: &StringSettings, rng: &mut StdRng) -> Self {
+        let period = sample_rate / frequency;
+        let damping = settings.damping.clamp(0.0, 0.9);
+        let w = 2.0 * PI * frequency / sample_rate;
+
+        // the loop lowpass delays the fundamental a little, the allpass makes up the fraction
+        let lpf_delay = (damping * w.sin()).atan2(1.0 - damping * w.cos()) / w;
+        let line = ((period - lpf_delay - 0.5).floor() as usize).max(2);
+        let fraction = period - lpf_delay - line as f64;
+
+        // loop gain for a 60dB drop of the fundamental after decay seconds,
+        // compensated for what the lowpass takes away at that frequency
+        let target = 10f64.powf(-3.0 * period / (settings.decay.max(0.01) * sample_rate));
+        let lpf_gain = (1.0 - damping) / (1.0 - 2.0 * damping * w.cos() + damping * damping).sqrt();
+        let feedback = (target / lpf_gain).min(0.9999);
+
+        // our comb line is one sample longer than its delay parameter. the tuning and loop gain
+        // above assume a one-pole lowpass in the loop, which the lpf comb's filter is not
+        let mut comb = CombFilter::new_one_pole_comb(line - 1, feedback, damping);
+        comb.set_fractional_delay(fraction);
+
+        let burst = match &settings.excitation {
+            Excitation::Noise => (0..line).map(|_| rng.gen_range(-1.0..1.0)).collect(),
+            Excitation::Custom(burst) => burst.clone(),
+        };
+
+        // softer notes are darker, the pick position notches out the partials that
+        // have a node there by subtracting a delayed copy of the burst
+        let mut brightness = OnePole::from_coefficient(0.8 * (1.0 - velocity));
+        // at least one sample of pick delay, high notes and picks near the bridge round down to none
+        let pick = ((settings.pick_position.clamp(0.01, 0.5) * period).round() as usize).max(2);
+        let mut pick_comb = CombFilter::new_feedforward(pick - 1, 1.0, CombType::NEGATIVE);
+        let excitation: Vec<f64> = burst.iter()
+            .chain(std::iter::repeat_n(&0.0, pick))
+            .map(|x| 0.5 * velocity * pick_comb.process_sample(brightness.process_sample(*x)))
+            .collect();
+
+        Self {
+            comb,
+            remaining: excitation.len() + (RELEASE_MARGIN * settings.decay * sample_rate) as usize,
+            excitation,
+            position: 0,
+        }
+    }
+
+    fn process_sample(&mut self) -> f64 {
+        let x = self.excitation.get(self.position).copied().unwrap_or(0.0);
+        self.position += 1;
+        self.remaining = self.remaining.saturating_sub(1);
+        self.comb.process_sample(x)
+    }
+}
+
+// plays a list of (time in seconds, midi note, velocity) events on plucked strings.
+// every event gets its own string, velocity 0 is ignored like a note off
+#[derive(Clone)]
+pub struct KarplusStrong {
+    sample_rate: f64,
+    settings: StringSettings,
+    // (start in samples, note, velocity), sorted by start
+    events: Vec<(usize, u8, u8)>,
+    next_event: usize,
+    time: usize,
+    voices: Vec<PluckedString>,
+    rng: StdRng,
+}
+
+impl KarplusStrong {
+    // seed picks the noise bursts
+    pub fn new(sample_rate: f64, settings: StringSettings, events: &[(f64, u8, u8)], seed: u64) -> Self {
+        let mut events: Vec<(usize, u8, u8)> = events.iter()
+            .filter(|(_, _, velocity)| *velocity > 0)
+            .map(|(time, note, velocity)| ((time.max(0.0) * sample_rate) as usize, *note, *velocity))
+            .collect();
+        events.sort_by_key(|(start, _, _)| *start);
+
+        Self {
+            sample_rate,
+            settings,
+            events,
+            next_event: 0,
+            time: 0,
+            voices: Vec::new(),
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    // length of the whole performance in seconds, until the last note has rung out
+    pub fn duration(&self) -> f64 {
+        let last = self.events.last().map_or(0, |(start, _, _)| *start) as f64 / self.sample_rate;
+        last + RELEASE_MARGIN * self.settings.decay
+    }
+
+    pub fn process_sample(&mut self) -> f64 {
+        while let Some((start, note, velocity)) = self.events.get(self.next_event).copied() {
+            if start > self.time {
+                break;
+            }
+            let frequency = midi_to_frequency(note);
+            let velocity = velocity.min(127) as f64 / 127.0;
+            self.voices.push(PluckedString::new(self.sample_rate, frequency, velocity, &self.settings, &mut self.rng));
+            self.next_event += 1;
+        }
+
+        let y = self.voices.iter_mut().map(|voice| voice.process_sample()).sum();
+        self.voices.retain(|voice| voice.remaining > 0);
+        self.time += 1;
+        y
+    }
+
+    // plays the events from the start into a mono wave
+    pub fn render(&mut self) -> Wave64 {
+        let length = (self.duration() * self.sample_rate) as usize;
+        let samples: Vec<f64> = (0..length).map(|_| self.process_sample()).collect();
+        Wave64::from_samples(self.sample_rate, &samples)
+    }
+}
+
+impl AudioNode for KarplusStrong {
+    const ID: u64 = 9982;
+    type Sample = f64;
+    type Inputs = typenum::U0;
+    type Outputs = typenum::U1;
+    type Setting = f64;
+
+    fn reset(&mut self) {
+    }
+
+    fn tick(
+        &mut self,
+        _input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
+    ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
+        let y = self.process_sample();
+        [y].into()
+    }
+}
+
+/// created a new plucked string generator where:
+///- events are (time in seconds, midi note, velocity)
+///- settings hold the excitation, pick position, decay and damping
+pub fn my_karplus_strong(sample_rate: f64, settings: StringSettings, events: &[(f64, u8, u8)]) -> An<KarplusStrong> {
+    An(KarplusStrong::new(sample_rate, settings, events, DEFAULT_SEED))
+}
+
+/// renders the events on plucked strings into a mono wave, to feed effect chains without a recording
+pub fn render_plucks(sample_rate: f64, settings: StringSettings, events: &[(f64, u8, u8)]) -> Wave64 {
+    KarplusStrong::new(sample_rate, settings, events, DEFAULT_SEED).render()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // period in samples from the autocorrelation peak, refined with a parabola
+    fn measured_period(samples: &[f64], expected: f64) -> f64 {
+        let correlation = |lag: usize| samples.iter().zip(samples[lag..].iter()).map(|(a, b)| a * b).sum::<f64>();
+        let lags = (expected * 0.9) as usize..(expected * 1.1) as usize + 1;
+        let best = lags.max_by(|a, b| correlation(*a).partial_cmp(&correlation(*b)).unwrap()).unwrap();
+        let (left, center, right) = (correlation(best - 1), correlation(best), correlation(best + 1));
+        best as f64 + 0.5 * (left - right) / (left - 2.0 * center + right)
+    }
+
+    #[test]
+    fn notes_are_in_tune() {
+        let sample_rate = 44100.0;
+        for note in [40, 57, 69, 81] {
+            let wave = render_plucks(sample_rate, StringSettings::default(), &[(0.0, note, 100)]);
+            let samples: Vec<f64> = (4410..26460).map(|i| wave.at(0, i)).collect();
+            let expected = sample_rate / midi_to_frequency(note);
+            let cents = 1200.0 * (measured_period(&samples, expected) / expected).log2();
+            assert!(cents.abs() < 3.0, "note {} was {} cents off", note, cents);
+        }
+    }
+
+    #[test]
+    fn short_pick_delays_play() {
+        let near_bridge = StringSettings { pick_position: 0.01, ..StringSettings::default() };
+        for (settings, note) in [(StringSettings::default(), 108), (near_bridge, 69)] {
+            let wave = render_plucks(44100.0, settings, &[(0.0, note, 100)]);
+            let samples: Vec<f64> = (0..wave.len()).map(|i| wave.at(0, i)).collect();
+            assert!(samples.iter().all(|x| x.is_finite()));
+            assert!(samples.iter().any(|x| *x != 0.0), "note {} was silent", note);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::f64::consts::PI;
+
+#[derive(Clone, Copy)]
+pub enum LfoShape {
+    SINE,
+    TRIANGLE,
+    SQUARE,
+    SAW,
+}
+
+#[derive(Clone)]
+pub struct Lfo {
+    shape: LfoShape,
+    rate: f64,
+    sample_rate: f64,
+    // phase is kept in [0, 1)
+    phase: f64,
+}
+
+impl Lfo {
+    // a negative rate runs the phase backwards
+    pub fn new(sample_rate: f64, rate: f64, shape: LfoShape, phase: f64) -> Self {
+        Self {
+            shape,
+            rate,
+            sample_rate,
+            phase: phase.rem_euclid(1.0),
+        }
+    }
+
+    pub fn set_rate(&mut self, rate: f64) {
+        self.rate = rate;
+    }
+
+    pub fn reset(&mut self, phase: f64) {
+        self.phase = phase.rem_euclid(1.0);
+    }
+
+    // current value in [-1, 1] without advancing the phase
+    pub fn value(&self) -> f64 {
+        match self.shape {
+            LfoShape::SINE => (2.0 * PI * self.phase).sin(),
+            LfoShape::TRIANGLE => 1.0 - 4.0 * (self.phase - 0.25 - (self.phase - 0.25).round()).abs(),
+            LfoShape::SQUARE => if self.phase < 0.5 { 1.0 } else { -1.0 },
+            LfoShape::SAW => 2.0 * self.phase - 1.0,
+        }
+    }
+
+    // advances the lfo by one sample and returns the new value
+    pub fn tick(&mut self) -> f64 {
+        self.phase += self.rate / self.sample_rate;
+        if self.phase >= 1.0 {
+            self.phase -= 1.0;
+        } else if self.phase < 0.0 {
+            self.phase += 1.0;
+        }
+        self.value()
+    }
+}
@@ -11,6 +11,23 @@ use delay::*;
 use reverb::*;
 use waveshaper::*;
 use effects::*;
+use flanger::*;
+use lfo::*;
+use bbd::*;
+use fdn::*;
+use plate::*;
+use convolution::*;
+use early_reflections::*;
+use analysis::*;
+use pitch_shifter::*;
+use shimmer::*;
+use envelope::*;
+use gated_reverb::*;
+use reverse_reverb::*;
+use karplus_strong::*;
+use diffuser::*;
+use oversampling::*;
+use transfer_curve::*;
 mod effects;
 mod waveshaper;
 mod reverb;
@@ -20,6 +37,26 @@ mod allpass;
 mod filter;
 mod chorus;
 mod comb;
+mod lfo;
+mod flanger;
+mod bbd;
+mod fdn;
+mod plate;
+mod convolution;
+mod early_reflections;
+mod analysis;
+mod pitch_shifter;
+mod shimmer;
+mod envelope;
+mod gated_reverb;
+mod reverse_reverb;
+mod karplus_strong;
+mod diffuser;
+mod oversampling;
+mod transfer_curve;
+mod triode;
+mod tone_stack;
+mod seed;
 
 const INPUT_DIR: &str= "res/input/";
 const OUTPUT_DIR: &str = "res/output/";
@@ -0,0 +1,212 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use fundsp::{audionode::AudioNode, prelude::An};
+use numeric_array::typenum::{self, U1};
+
+pub const DEFAULT_OVERSAMPLING: usize = 4;
+pub const MAX_OVERSAMPLING: usize = 16;
+// taps of each polyphase branch, the full half-band filter is 4 * HALF_BAND_TAPS - 1 long
+const HALF_BAND_TAPS: usize = 24;
+const KAISER_BETA: f64 = 8.0;
+
+// kaiser windowed half-band lowpass. only the even taps and the center tap are non zero,
+// so each 2x step splits into a short fir branch and a pure delay branch
+#[derive(Clone)]
+struct HalfBand {
+    // even taps, doubled so upsampling keeps the level
+    taps: Vec<f64>,
+    up_history: VecDeque<f64>,
+    down_even: VecDeque<f64>,
+    down_odd: VecDeque<f64>,
+}
+
+impl HalfBand {
+    fn new() -> Self {
+        let length = 4 * HALF_BAND_TAPS - 1;
+        let center = (length / 2) as f64;
+
+        let mut taps: Vec<f64> = (0..2 * HALF_BAND_TAPS)
+            .map(|j| {
+                let t = (2 * j) as f64 - center;
+                let sinc = (PI * t / 2.0).sin() / (PI * t / 2.0);
+                let window = bessel_i0(KAISER_BETA * (1.0 - (t / center).powi(2)).max(0.0).sqrt()) / bessel_i0(KAISER_BETA);
+                sinc * window
+            })
+            .collect();
+        // unit gain at dc for the fir branch
+        let sum: f64 = taps.iter().sum();
+        taps.iter_mut().for_each(|tap| *tap /= sum);
+
+        Self {
+            taps,
+            up_history: VecDeque::from(vec![0.0; 2 * HALF_BAND_TAPS]),
+            down_even: VecDeque::from(vec![0.0; 2 * HALF_BAND_TAPS]),
+            down_odd: VecDeque::from(vec![0.0; HALF_BAND_TAPS]),
+        }
+    }
+
+    // one sample in, two samples out at twice the rate
+    fn upsample(&mut self, x: f64) -> (f64, f64) {
+        self.up_history.pop_back();
+        self.up_history.push_front(x);
+        let even = self.taps.iter().zip(self.up_history.iter()).map(|(h, x)| h * x).sum();
+        // the center tap is half a sample later than the middle of the fir branch
+        let odd = self.up_history[HALF_BAND_TAPS - 1];
+        (even, odd)
+    }
+
+    // two samples in at twice the rate, one sample out
+    fn downsample(&mut self, even: f64, odd: f64) -> f64 {
+        self.down_even.pop_back();
+        self.down_even.push_front(even);
+        let fir: f64 = self.taps.iter().zip(self.down_even.iter()).map(|(h, x)| h * x).sum();
+        let center = self.down_odd[HALF_BAND_TAPS - 1];
+        self.down_odd.pop_back();
+        self.down_odd.push_front(odd);
+        0.5 * (fir + center)
+    }
+
+    // delay of an up and down pair, in samples at the higher rate
+    fn latency() -> f64 {
+        2.0 * (2 * HALF_BAND_TAPS - 1) as f64
+    }
+
+    fn reset(&mut self) {
+        self.up_history.iter_mut().for_each(|x| *x = 0.0);
+        self.down_even.iter_mut().for_each(|x| *x = 0.0);
+        self.down_odd.iter_mut().for_each(|x| *x = 0.0);
+    }
+}
+
+// zeroth order modified bessel function of the first kind, for the kaiser window
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    for k in 1..50 {
+        term *= (x / (2.0 * k as f64)).powi(2);
+        sum += term;
+        if term < sum * 1e-16 {
+            break;
+        }
+    }
+    sum
+}
+
+// runs a mono node at 2, 4, 8 or 16 times the sample rate, with a cascade of half-band
+// filters on the way up and down. the wrapped node sees the higher rate, so anything in it
+// that was built with a sample rate has to be built with sample_rate * factor
+#[derive(Clone)]
+pub struct Oversampler<X: AudioNode<Sample = f64, Inputs = U1, Outputs = U1>> {
+    node: X,
+    factor: usize,
+    stages: Vec<HalfBand>,
+    buffer: Vec<f64>,
+    scratch: Vec<f64>,
+}
+
+impl<X: AudioNode<Sample = f64, Inputs = U1, Outputs = U1>> Oversampler<X> {
+    // factor is rounded up to a power of two and kept in [1, 16], 1 runs the node as is
+    pub fn new(factor: usize, node: X) -> Self {
+        let factor = factor.clamp(1, MAX_OVERSAMPLING).next_power_of_two();
+        let stages = (0..factor.trailing_zeros()).map(|_| HalfBand::new()).collect();
+
+        Self {
+            node,
+            factor,
+            stages,
+            buffer: Vec::with_capacity(factor),
+            scratch: Vec::with_capacity(factor),
+        }
+    }
+
+    pub fn factor(&self) -> usize {
+        self.factor
+    }
+
+    pub fn process_sample(&mut self, x: f64) -> f64 {
+        self.buffer.clear();
+        self.buffer.push(x);
+        for stage in self.stages.iter_mut() {
+            self.scratch.clear();
+            for x in self.buffer.iter() {
+                let (even, odd) = stage.upsample(*x);
+                self.scratch.push(even);
+                self.scratch.push(odd);
+            }
+            std::mem::swap(&mut self.buffer, &mut self.scratch);
+        }
+
+        for x in self.buffer.iter_mut() {
+            *x = self.node.tick(&[*x].into())[0];
+        }
+
+        for stage in self.stages.iter_mut().rev() {
+            self.scratch.clear();
+            for pair in self.buffer.chunks(2) {
+                self.scratch.push(stage.downsample(pair[0], pair[1]));
+            }
+            std::mem::swap(&mut self.buffer, &mut self.scratch);
+        }
+        self.buffer[0]
+    }
+}
+
+impl<X: AudioNode<Sample = f64, Inputs = U1, Outputs = U1>> AudioNode for Oversampler<X> {
+    const ID: u64 = 9979;
+    type Sample = f64;
+    type Inputs = typenum::U1;
+    type Outputs = typenum::U1;
+    type Setting = f64;
+
+    fn reset(&mut self) {
+        self.node.reset();
+        self.stages.iter_mut().for_each(|stage| stage.reset());
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.node.set_sample_rate(sample_rate * self.factor as f64);
+    }
+
+    fn tick(
+        &mut self,
+        input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
+    ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
+        let y = self.process_sample(input[0]);
+        [y].into()
+    }
+
+    fn latency(&mut self) -> Option<f64> {
+        // stage i runs at 2^(i + 1) times the base rate
+        let filters: f64 = (1..=self.stages.len()).map(|i| HalfBand::latency() / (1 << i) as f64).sum();
+        let node = self.node.latency().unwrap_or(0.0) / self.factor as f64;
+        Some(filters + node)
+    }
+}
+
+/// runs node at factor (2, 4, 8 or 16) times the sample rate
+pub fn oversample<X: AudioNode<Sample = f64, Inputs = U1, Outputs = U1>>(factor: usize, node: An<X>) -> An<Oversampler<X>> {
+    An(Oversampler::new(factor, node.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fundsp::prelude::pass;
+
+    #[test]
+    fn passes_the_audio_band_and_reports_its_latency() {
+        let sample_rate = 44100.0;
+        for factor in [2, 4, 8, 16] {
+            let mut node = oversample(factor, pass());
+            let latency = node.latency().unwrap();
+
+            let sine = |t: f64| (2.0 * PI * 1000.0 * t / sample_rate).sin();
+            let error = (0..8192)
+                .map(|i| (node.filter_mono(sine(i as f64)) - sine(i as f64 - latency)).abs())
+                .skip(4096)
+                .fold(0.0, f64::max);
+            assert!(error < 1e-3, "factor {} was off by {}", factor, error);
+        }
+    }
+}
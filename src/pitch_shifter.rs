@@ -0,0 +1,87 @@
+use std::f64::consts::PI;
+
+use circular_buffer::CircularBuffer;
+use fundsp::{audionode::AudioNode, prelude::An};
+use numeric_array::typenum::{self};
+
+const MAX_WINDOW: usize = 8192;
+
+// delay-line pitch shifter. two taps sweep through a short window at a speed set by the
+// pitch ratio, half a window apart, and are crossfaded so one is always far from the jump
+#[derive(Clone)]
+pub struct PitchShifter {
+    buffer: Box<CircularBuffer::<MAX_WINDOW, f64>>,
+    window: f64,
+    ratio: f64,
+    // position of the first tap in the window, in [0, 1)
+    phase: f64,
+}
+
+impl PitchShifter {
+    // window is in ms, 30 to 100ms works well for most material
+    pub fn new(sample_rate: f64, semitones: f64, window_ms: f64) -> Self {
+        let mut buffer = CircularBuffer::<MAX_WINDOW, f64>::boxed();
+        for _ in 0..MAX_WINDOW {
+            buffer.push_back(0.0);
+        }
+
+        let window = (window_ms / 1000.0 * sample_rate).clamp(4.0, (MAX_WINDOW - 2) as f64);
+
+        Self {
+            buffer,
+            window,
+            ratio: 2f64.powf(semitones / 12.0),
+            phase: 0.0,
+        }
+    }
+
+    pub fn set_semitones(&mut self, semitones: f64) {
+        self.ratio = 2f64.powf(semitones / 12.0);
+    }
+
+    pub fn process_sample(&mut self, x: f64) -> f64 {
+        self.buffer.push_front(x);
+
+        // reading faster than we write shortens the delay, so a higher pitch sweeps it down
+        self.phase = (self.phase + (1.0 - self.ratio) / self.window).rem_euclid(1.0);
+        let other = (self.phase + 0.5).rem_euclid(1.0);
+
+        // sin^2 and cos^2 crossfade, each tap is silent where its delay wraps around
+        let gain = (PI * self.phase).sin().powi(2);
+        gain * self.read(self.phase * self.window) + (1.0 - gain) * self.read(other * self.window)
+    }
+
+    fn read(&self, delay: f64) -> f64 {
+        let index = delay.floor() as usize;
+        let frac = delay - index as f64;
+        let a = self.buffer.get(index).unwrap();
+        let b = self.buffer.get(index + 1).unwrap();
+        a + (b - a) * frac
+    }
+}
+
+impl AudioNode for PitchShifter {
+    const ID: u64 = 9987;
+    type Sample = f64;
+    type Inputs = typenum::U1;
+    type Outputs = typenum::U1;
+    type Setting = f64;
+
+    fn reset(&mut self) {
+    }
+
+    fn tick(
+        &mut self,
+        input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
+    ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
+        let y = self.process_sample(input[0]);
+        [y].into()
+    }
+}
+
+/// created a new pitch shifter where:
+///- semitones can be fractional and negative
+///- window is in ms
+pub fn my_pitch_shifter(sample_rate: f64, semitones: f64, window_ms: f64) -> An<PitchShifter> {
+    An(PitchShifter::new(sample_rate, semitones, window_ms))
+}
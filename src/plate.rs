@@ -0,0 +1,190 @@
+use fundsp::{audionode::AudioNode, prelude::An};
+use numeric_array::typenum::{self};
+
+use crate::allpass::AllPass;
+use crate::delay::{Delay, MAX_DELAY};
+use crate::lfo::{Lfo, LfoShape};
+
+// the original plate runs at 29761Hz, every length below is in samples at that rate
+const DATTORRO_RATE: f64 = 29761.0;
+const INPUT_DIFFUSERS: [(f64, f64); 4] = [(142.0, 0.75), (107.0, 0.75), (379.0, 0.625), (277.0, 0.625)];
+// (modulated allpass, first delay, second allpass, second delay) for each half of the tank
+const TANK_LEFT: [f64; 4] = [672.0, 4453.0, 1800.0, 3720.0];
+const TANK_RIGHT: [f64; 4] = [908.0, 4217.0, 2656.0, 3163.0];
+const DECAY_DIFFUSION_1: f64 = 0.7;
+const DECAY_DIFFUSION_2: f64 = 0.5;
+const MAX_EXCURSION: f64 = 16.0;
+const OUTPUT_GAIN: f64 = 0.6;
+
+// output taps as (source, position, sign). the sources are
+// 0: left first delay, 1: left second allpass, 2: left second delay,
+// 3: right first delay, 4: right second allpass, 5: right second delay
+const LEFT_TAPS: [(usize, f64, f64); 7] = [
+    (3, 266.0, 1.0), (3, 2974.0, 1.0), (4, 1913.0, -1.0), (5, 1996.0, 1.0),
+    (0, 1990.0, -1.0), (1, 187.0, -1.0), (2, 1066.0, -1.0),
+];
+const RIGHT_TAPS: [(usize, f64, f64); 7] = [
+    (0, 353.0, 1.0), (0, 3627.0, 1.0), (1, 1228.0, -1.0), (2, 2673.0, 1.0),
+    (3, 2111.0, -1.0), (4, 335.0, -1.0), (5, 121.0, -1.0),
+];
+
+#[derive(Clone)]
+struct TankHalf {
+    modulated: AllPass,
+    modulated_delay: f64,
+    first_delay: Delay,
+    first_length: usize,
+    diffuser: AllPass,
+    second_delay: Delay,
+    second_length: usize,
+    lfo: Lfo,
+    damping_state: f64,
+    output: f64,
+}
+
+impl TankHalf {
+    fn new(sample_rate: f64, lengths: [f64; 4], lfo: Lfo) -> Self {
+        let scale = sample_rate / DATTORRO_RATE;
+        let first_length = (lengths[1] * scale) as usize;
+        let second_length = (lengths[3] * scale) as usize;
+
+        Self {
+            // the first decay diffuser runs with a negative coefficient
+            modulated: AllPass::new((lengths[0] * scale) as usize, -DECAY_DIFFUSION_1),
+            modulated_delay: lengths[0] * scale,
+            first_delay: Delay::new(first_length, 0.0),
+            first_length,
+            diffuser: AllPass::new((lengths[2] * scale) as usize, DECAY_DIFFUSION_2),
+            second_delay: Delay::new(second_length, 0.0),
+            second_length,
+            lfo,
+            damping_state: 0.0,
+            output: 0.0,
+        }
+    }
+
+    fn process_sample(&mut self, x: f64, excursion: f64, decay: f64, damping: f64) -> f64 {
+        let delay = self.modulated_delay + excursion * self.lfo.tick();
+        let y = self.modulated.process_sample_fractional(x, delay);
+        let y = self.first_delay.process_sample(y, self.first_length);
+
+        self.damping_state = (1.0 - damping) * y + damping * self.damping_state;
+
+        let y = self.diffuser.process_sample(self.damping_state * decay);
+        self.output = self.second_delay.process_sample(y, self.second_length);
+        self.output
+    }
+}
+
+#[derive(Clone)]
+pub struct Plate {
+    pre_delay: Delay,
+    pre_delay_samples: usize,
+    bandwidth: f64,
+    bandwidth_state: f64,
+    diffusers: Vec<AllPass>,
+    left: TankHalf,
+    right: TankHalf,
+    decay: f64,
+    damping: f64,
+    excursion: f64,
+    scale: f64,
+}
+
+impl Plate {
+    pub fn new(sample_rate: f64, pre_delay_ms: f64, bandwidth: f64, decay: f64, damping: f64, mod_depth: f64, mod_rate: f64) -> Self {
+        let scale = sample_rate / DATTORRO_RATE;
+        let mut pre_delay_samples = (pre_delay_ms.max(0.0) / 1000.0 * sample_rate) as usize;
+        // the line is read one sample past the pre-delay
+        if pre_delay_samples > MAX_DELAY - 1 {
+            println!("Pre-delay for Plate too high. Setting to {} ms", (MAX_DELAY - 1) as f64 * 1000.0 / sample_rate);
+            pre_delay_samples = MAX_DELAY - 1;
+        }
+
+        let diffusers = INPUT_DIFFUSERS.iter()
+            .map(|(length, gain)| AllPass::new((length * scale) as usize, *gain))
+            .collect();
+
+        // the two halves are modulated in quadrature
+        let left = TankHalf::new(sample_rate, TANK_LEFT, Lfo::new(sample_rate, mod_rate, LfoShape::SINE, 0.0));
+        let right = TankHalf::new(sample_rate, TANK_RIGHT, Lfo::new(sample_rate, mod_rate, LfoShape::SINE, 0.25));
+
+        Self {
+            pre_delay: Delay::new(pre_delay_samples, 0.0),
+            pre_delay_samples,
+            bandwidth: bandwidth.clamp(0.0, 1.0),
+            bandwidth_state: 0.0,
+            diffusers,
+            left,
+            right,
+            decay: decay.clamp(0.0, 0.9999),
+            damping: damping.clamp(0.0, 1.0),
+            excursion: mod_depth.clamp(0.0, 1.0) * MAX_EXCURSION * scale,
+            scale,
+        }
+    }
+
+    pub fn process_sample(&mut self, x: f64) -> (f64, f64) {
+        let x = self.pre_delay.process_sample(x, self.pre_delay_samples);
+
+        self.bandwidth_state = self.bandwidth * x + (1.0 - self.bandwidth) * self.bandwidth_state;
+        let mut diffused = self.bandwidth_state;
+        for diffuser in self.diffusers.iter_mut() {
+            diffused = diffuser.process_sample(diffused);
+        }
+
+        // figure-eight, each half is fed by the other one's output from the last sample
+        let left_feed = diffused + self.decay * self.right.output;
+        let right_feed = diffused + self.decay * self.left.output;
+        self.left.process_sample(left_feed, self.excursion, self.decay, self.damping);
+        self.right.process_sample(right_feed, self.excursion, self.decay, self.damping);
+
+        let left = LEFT_TAPS.iter().map(|tap| self.tap(*tap)).sum::<f64>();
+        let right = RIGHT_TAPS.iter().map(|tap| self.tap(*tap)).sum::<f64>();
+
+        (OUTPUT_GAIN * left, OUTPUT_GAIN * right)
+    }
+
+    fn tap(&self, (source, position, sign): (usize, f64, f64)) -> f64 {
+        let index = (position * self.scale) as usize;
+        let value = match source {
+            0 => self.left.first_delay.tap(index),
+            1 => self.left.diffuser.tap(index),
+            2 => self.left.second_delay.tap(index),
+            3 => self.right.first_delay.tap(index),
+            4 => self.right.diffuser.tap(index),
+            _ => self.right.second_delay.tap(index),
+        };
+        sign * value
+    }
+}
+
+impl AudioNode for Plate {
+    const ID: u64 = 9989;
+    type Sample = f64;
+    type Inputs = typenum::U1;
+    type Outputs = typenum::U2;
+    type Setting = f64;
+
+    fn reset(&mut self) {
+    }
+
+    fn tick(
+        &mut self,
+        input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
+    ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
+        let (left, right) = self.process_sample(input[0]);
+        [left, right].into()
+    }
+}
+
+/// created a new dattorro plate reverb where:
+///- pre_delay is in ms
+///- bandwidth is the input lowpass in [0, 1], 1.0 lets everything through
+///- decay is in [0, 0.9999]
+///- damping is the tank lowpass in [0, 1], 0.0 means no damping
+///- mod_depth is in [0, 1]
+///- mod_rate is in Hz
+pub fn my_plate_reverb(sample_rate: f64, pre_delay_ms: f64, bandwidth: f64, decay: f64, damping: f64, mod_depth: f64, mod_rate: f64) -> An<Plate> {
+    An(Plate::new(sample_rate, pre_delay_ms, bandwidth, decay, damping, mod_depth, mod_rate))
+}
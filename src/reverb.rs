@@ -1,9 +1,24 @@
 use circular_buffer::CircularBuffer;
-use fundsp::{audionode::AudioNode, prelude::An, Num};
+use fundsp::{audionode::AudioNode, prelude::An};
 use numeric_array::typenum::{*, self};
-use rand::Rng;
-
-use crate::{comb::{CombFilter, CombType}, allpass::AllPass, delay};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::{comb::{CombFilter, CombType}, allpass::AllPass, delay::{Delay, MAX_DELAY}};
+use crate::early_reflections::{EarlyReflections, RoomPreset};
+use crate::lfo::{Lfo, LfoShape};
+use crate::seed::DEFAULT_SEED;
+
+// freeverb tuning, comb and allpass lengths are in samples at 44.1kHz
+const FREEVERB_COMBS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const FREEVERB_ALLPASSES: [usize; 4] = [556, 441, 341, 225];
+const FREEVERB_STEREO_SPREAD: usize = 23;
+const FREEVERB_ALLPASS_GAIN: f64 = 0.5;
+const FREEVERB_FIXED_GAIN: f64 = 0.015;
+const FREEVERB_SCALE_WET: f64 = 3.0;
+const FREEVERB_SCALE_DRY: f64 = 2.0;
+const FREEVERB_SCALE_DAMP: f64 = 0.4;
+const FREEVERB_SCALE_ROOM: f64 = 0.28;
+const FREEVERB_OFFSET_ROOM: f64 = 0.7;
 
 #[derive(Clone)]
 pub enum ReverbType {
@@ -11,192 +26,514 @@ pub enum ReverbType {
     Schroeder,
     LpfComb,
     Moorer,
+    Freeverb,
 }
 
-#[derive(Clone)]
-pub struct Reverb {
-    combs: Vec<CombFilter>,
-    allpasses: Vec<AllPass>,
-    decay: f64,
-    reverb_type: ReverbType,
+// ms use the floored length as the delay parameter, so the line ends up one sample longer.
+// samples are the exact line length at 44.1kHz and get scaled to the sample rate
+#[derive(Clone, Copy)]
+pub enum DelayLength {
+    Ms(f64),
+    Samples(usize),
+    // a random delay in ms, picked from [min, max) with the reverb seed
+    RandomMs(f64, f64),
 }
 
-impl Reverb {
-    pub fn new(sample_rate: f64, decay: f64, reverb_type: ReverbType, damp: f64) -> Self {
-        let mut combs = Vec::new();
-        let mut allpasses = Vec::new();
+#[derive(Clone, Copy)]
+pub enum CombGain {
+    // 60dB of attenuation after decay seconds
+    Decay,
+    // the decay gain scaled by (1 - damping), keeps lowpass combs from ringing longer
+    DampedDecay,
+    Fixed(f64),
+}
 
-        let mut rng = rand::thread_rng();
+#[derive(Clone, Copy)]
+pub struct CombSpec {
+    pub delay: DelayLength,
+    pub gain: CombGain,
+    // lowpass in the feedback path, 0.0 is a plain comb
+    pub damping: f64,
+    // freeverb's one-pole damping instead of the lpf comb
+    pub freeverb: bool,
+}
 
-        match reverb_type {
-            ReverbType::CombReverb => {
-                let delays_ms = [21.0, 26.0, 31.0, 37.0];
-                for i in 0..4 {
-                    // random delay between 3 and 50 ms
-                    let delay_ms = delays_ms[i];
-                    let delay_seconds = delay_ms / 1000.0;
-                    let delay_samples = (delay_seconds * sample_rate).floor() as usize;
+#[derive(Clone, Copy)]
+pub struct AllPassSpec {
+    pub delay: DelayLength,
+    pub gain: f64,
+    pub freeverb: bool,
+}
 
-                    let power = -(3.0 * delay_seconds as f64) / (decay) ;
+#[derive(Clone)]
+pub enum ReverbStage {
+    // allpasses in series
+    AllPasses(Vec<AllPassSpec>),
+    // combs in parallel, summed and scaled by gain. with alternate_signs every other comb
+    // is subtracted, with delayed_output the combs output the sample leaving the line
+    Combs {
+        combs: Vec<CombSpec>,
+        gain: f64,
+        alternate_signs: bool,
+        delayed_output: bool,
+    },
+}
 
-                    let g = 10f64.powf(power);
+// stages run in series. even chains go to the left channel and odd chains to the right,
+// a single chain is sent to both channels
+#[derive(Clone)]
+pub struct ReverbTopology {
+    pub input_gain: f64,
+    pub chains: Vec<Vec<ReverbStage>>,
+    // what the wet and dry controls in [0, 1] are scaled by
+    pub wet_scale: f64,
+    pub dry_scale: f64,
+    // how much the left and right chains are kept apart, in [0, 1]
+    pub width: f64,
+}
 
-                    //println!("\n## COMB {} ## \ndelay_ms: {}, \ndelay_seconds: {}, \ndelay_samples: {},\npower: {:.2}\ng: {:.5}", i, delay_ms, delay_seconds, delay_samples, power, g);
-                    let comb_type = CombType::POSITIVE;
-                    combs.push(CombFilter::new_comb(delay_samples as usize, g, comb_type));
-                }
-            },
-            ReverbType::Schroeder => {
-                let mut delay_ms = 15.0;
-                for i in 0..4 {
-                    // random delay between 3 and 50 ms
-                    delay_ms = delay_ms * 1.5;
-                    let delay_seconds = delay_ms / 1000.0;
-                    let delay_samples = (delay_seconds * sample_rate).floor() as usize;
-                    let decay_samples = (decay * sample_rate).floor() as usize;
-
-                    let power = -(3.0 * delay_samples as f64) / (decay_samples as f64) ;
-
-                    let g = 10f64.powf(power);
-
-                    //println!("\n## COMB {} ## \ndelay_ms: {}, \ndelay_seconds: {}, \ndelay_samples: {},\npower: {:.2}\ng: {:.5}", i, delay_ms, delay_seconds, delay_samples, power, g);
-                    let comb_type = CombType::POSITIVE;
-                    combs.push(CombFilter::new_comb(delay_samples as usize, g, comb_type));
-                }
+impl Default for ReverbTopology {
+    fn default() -> Self {
+        Self {
+            input_gain: 1.0,
+            chains: Vec::new(),
+            wet_scale: 1.0,
+            dry_scale: 0.0,
+            width: 1.0,
+        }
+    }
+}
 
-                for _ in 0..4 {
-                    // random delay between 1 and 5 ms
-                    let delay = ((rng.gen_range(2.0..8.0) / 1000.0) * sample_rate).floor() as usize;
-                    allpasses.push(AllPass::new(delay, 0.707));
+impl ReverbType {
+    // decay is in seconds (the room size for freeverb), damp is in [0, 1]
+    pub fn topology(&self, decay: f64, damp: f64) -> ReverbTopology {
+        // 6 combs growing by 1.5 from 22.5ms
+        let growing_combs = |count: usize, gain: CombGain, damping: f64| {
+            (1..=count)
+                .map(|i| CombSpec { delay: DelayLength::Ms(15.0 * 1.5f64.powi(i as i32)), gain, damping, freeverb: false })
+                .collect::<Vec<_>>()
+        };
+        let random_allpasses = |count: usize| {
+            vec![AllPassSpec { delay: DelayLength::RandomMs(2.0, 8.0), gain: 0.707, freeverb: false }; count]
+        };
+        let damp = damp.clamp(0.0, 0.9999);
+
+        match self {
+            ReverbType::CombReverb => ReverbTopology {
+                input_gain: 1.0,
+                chains: vec![vec![
+                    ReverbStage::Combs {
+                        combs: [21.0, 26.0, 31.0, 37.0].iter()
+                            .map(|ms| CombSpec { delay: DelayLength::Ms(*ms), gain: CombGain::Decay, damping: 0.0, freeverb: false })
+                            .collect(),
+                        gain: 0.25,
+                        alternate_signs: true,
+                        delayed_output: false,
+                    },
+                ]],
+                ..Default::default()
+            },
+            ReverbType::Schroeder => ReverbTopology {
+                input_gain: 1.0,
+                chains: vec![vec![
+                    ReverbStage::AllPasses(random_allpasses(2)),
+                    ReverbStage::Combs {
+                        combs: growing_combs(4, CombGain::Decay, 0.0),
+                        gain: 1.0,
+                        alternate_signs: true,
+                        delayed_output: false,
+                    },
+                    ReverbStage::AllPasses(random_allpasses(2)),
+                ]],
+                ..Default::default()
+            },
+            ReverbType::LpfComb => ReverbTopology {
+                input_gain: 1.0,
+                chains: vec![vec![
+                    ReverbStage::Combs {
+                        combs: growing_combs(6, CombGain::DampedDecay, damp),
+                        gain: 0.8,
+                        alternate_signs: true,
+                        delayed_output: false,
+                    },
+                ]],
+                ..Default::default()
+            },
+            ReverbType::Moorer => ReverbTopology {
+                input_gain: 1.0,
+                chains: vec![vec![
+                    ReverbStage::Combs {
+                        combs: growing_combs(6, CombGain::DampedDecay, damp),
+                        gain: 0.75,
+                        alternate_signs: true,
+                        delayed_output: false,
+                    },
+                    ReverbStage::AllPasses(random_allpasses(1)),
+                ]],
+                ..Default::default()
+            },
+            ReverbType::Freeverb => {
+                let room = decay.clamp(0.0, 1.0) * FREEVERB_SCALE_ROOM + FREEVERB_OFFSET_ROOM;
+                let damping = damp.clamp(0.0, 1.0) * FREEVERB_SCALE_DAMP;
+
+                let chain = |spread: usize| vec![
+                    ReverbStage::Combs {
+                        combs: FREEVERB_COMBS.iter()
+                            .map(|length| CombSpec { delay: DelayLength::Samples(length + spread), gain: CombGain::Fixed(room), damping, freeverb: true })
+                            .collect(),
+                        gain: 1.0,
+                        alternate_signs: false,
+                        delayed_output: true,
+                    },
+                    ReverbStage::AllPasses(FREEVERB_ALLPASSES.iter()
+                        .map(|length| AllPassSpec { delay: DelayLength::Samples(length + spread), gain: FREEVERB_ALLPASS_GAIN, freeverb: true })
+                        .collect()),
+                ];
+
+                ReverbTopology {
+                    // freeverb sums both input channels, a mono input counts twice
+                    input_gain: 2.0 * FREEVERB_FIXED_GAIN,
+                    chains: vec![chain(0), chain(FREEVERB_STEREO_SPREAD)],
+                    wet_scale: FREEVERB_SCALE_WET,
+                    dry_scale: FREEVERB_SCALE_DRY,
+                    width: 1.0,
                 }
             },
-            ReverbType::LpfComb => {
-                let mut delay_ms = 15.0;
-                for i in 0..6 {
-                    // random delay between 3 and 50 ms
-                    delay_ms = delay_ms * 1.5;
-                    let delay_seconds = delay_ms / 1000.0;
-                    let delay_samples = (delay_seconds * sample_rate).floor() as usize;
-                    let decay_samples = (decay * sample_rate).floor() as usize;
-
-                    let power = -(3.0 * delay_samples as f64) / (decay_samples as f64) ;
-
-                    let g = 10f64.powf(power);
-                    
-                    let damp = damp.clamp(0.0, 0.9999);
+        }
+    }
+}
 
-                    let new_g = g * (1.0 - damp);
+// slow wander of one delay length around its nominal value, in samples
+#[derive(Clone)]
+struct DelayModulator {
+    delay: f64,
+    depth: f64,
+    lfo: Lfo,
+}
 
-                    println!("\n## COMB {} ## \ndelay_ms: {}, \ndelay_seconds: {}, \ndelay_samples: {},\npower: {:.2}\ng: {:.5}", i, delay_ms, delay_seconds, delay_samples, power, new_g);
-                    combs.push(CombFilter::new_lpf_comb(delay_samples as usize, new_g, damp));
-                }
-            },
-            ReverbType::Moorer => {
-                let mut delay_ms = 15.0;
-                for i in 0..6 {
-                    // random delay between 3 and 50 ms
-                    delay_ms = delay_ms * 1.5;
-                    let delay_seconds = delay_ms / 1000.0;
-                    let delay_samples = (delay_seconds * sample_rate).floor() as usize;
-                    let decay_samples = (decay * sample_rate).floor() as usize;
+impl DelayModulator {
+    // every line gets its own rate and phase, so the lines never move together
+    fn new(sample_rate: f64, delay: usize, depth_ms: f64, rate: f64, rng: &mut StdRng) -> Self {
+        let delay = delay as f64;
+        let rate = rate * rng.gen_range(0.5..1.5);
+        let phase = rng.gen_range(0.0..1.0);
 
-                    let power = -(3.0 * delay_samples as f64) / (decay_samples as f64) ;
+        Self {
+            delay,
+            // never read ahead of the write position
+            depth: (depth_ms.max(0.0) / 1000.0 * sample_rate).min(delay),
+            lfo: Lfo::new(sample_rate, rate, LfoShape::SINE, phase),
+        }
+    }
 
-                    let g = 10f64.powf(power);
-                    
-                    let damp = damp.clamp(0.0, 0.9999);
+    fn tick(&mut self) -> f64 {
+        self.delay + self.depth * self.lfo.tick()
+    }
+}
 
-                    let new_g = g * (1.0 - damp);
+#[derive(Clone)]
+enum Stage {
+    // without modulators the delays are read at their integer lengths
+    AllPasses {
+        allpasses: Vec<AllPass>,
+        modulators: Vec<DelayModulator>,
+    },
+    Combs {
+        combs: Vec<CombFilter>,
+        modulators: Vec<DelayModulator>,
+        gain: f64,
+        alternate_signs: bool,
+        delayed_output: bool,
+    },
+}
 
-                    println!("\n## COMB {} ## \ndelay_ms: {}, \ndelay_seconds: {}, \ndelay_samples: {},\npower: {:.2}\ng: {:.5}", i, delay_ms, delay_seconds, delay_samples, power, new_g);
-                    combs.push(CombFilter::new_lpf_comb(delay_samples as usize, new_g, damp));
+impl Stage {
+    fn process_sample(&mut self, x: f64) -> f64 {
+        match self {
+            Stage::AllPasses { allpasses, modulators } => {
+                if modulators.is_empty() {
+                    allpasses.iter_mut().fold(x, |y, allpass| allpass.process_sample(y))
+                } else {
+                    allpasses.iter_mut().zip(modulators.iter_mut())
+                        .fold(x, |y, (allpass, modulator)| allpass.process_sample_fractional(y, modulator.tick()))
                 }
-
-                for _ in 0..1 {
-                    let delay = ((rng.gen_range(2.0..8.0) / 1000.0) * sample_rate).floor() as usize;
-                    allpasses.push(AllPass::new(delay, 0.707));
+            },
+            Stage::Combs { combs, modulators, gain, alternate_signs, delayed_output } => {
+                let mut y = 0.0;
+                for (i, comb) in combs.iter_mut().enumerate() {
+                    let out = match modulators.get_mut(i) {
+                        Some(modulator) => {
+                            let delay = modulator.tick();
+                            if *delayed_output {
+                                let out = comb.peek_fractional(delay);
+                                comb.process_sample_fractional(x, delay);
+                                out
+                            } else {
+                                comb.process_sample_fractional(x, delay)
+                            }
+                        },
+                        None if *delayed_output => {
+                            let out = comb.peek();
+                            comb.process_sample(x);
+                            out
+                        },
+                        None => comb.process_sample(x),
+                    };
+                    if *alternate_signs && i % 2 == 1 {
+                        y -= out;
+                    } else {
+                        y += out;
+                    }
                 }
+                y * *gain
             },
         }
+    }
+}
+
+#[derive(Clone)]
+pub struct Reverb {
+    chains: Vec<Vec<Stage>>,
+    input_gain: f64,
+    decay: f64,
+    // stereo width, wet and dry controls in [0, 1], scaled by the topology
+    width: f64,
+    wet: f64,
+    dry: f64,
+    wet_scale: f64,
+    dry_scale: f64,
+    sample_rate: f64,
+    pre_delay: Option<Delay>,
+    pre_delay_samples: usize,
+    early_reflections: Option<EarlyReflections>,
+    // 0.0 is only the late reverb, 1.0 only the early reflections
+    er_balance: f64,
+    // 1.0 is fully wet
+    mix: f64,
+    seed: u64,
+}
+
+impl Reverb {
+    // seed picks the random allpass delays
+    pub fn new(sample_rate: f64, decay: f64, reverb_type: ReverbType, damp: f64, seed: u64) -> Self {
+        Self::from_topology(sample_rate, decay, &reverb_type.topology(decay, damp), seed)
+    }
+
+    pub fn from_topology(sample_rate: f64, decay: f64, topology: &ReverbTopology, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        // length used as the delay parameter of our lines
+        let mut delay_samples = |length: DelayLength| match length {
+            DelayLength::Ms(ms) => (ms / 1000.0 * sample_rate).floor() as usize,
+            DelayLength::Samples(samples) => ((samples as f64 * sample_rate / 44100.0) as usize).max(1) - 1,
+            DelayLength::RandomMs(min, max) => ((rng.gen_range(min..max) / 1000.0) * sample_rate).floor() as usize,
+        };
+
+        let mut chains = Vec::new();
+        for chain in topology.chains.iter() {
+            let mut stages = Vec::new();
+            for stage in chain.iter() {
+                stages.push(match stage {
+                    ReverbStage::AllPasses(specs) => Stage::AllPasses {
+                        allpasses: specs.iter().map(|spec| {
+                            let delay = delay_samples(spec.delay);
+                            if spec.freeverb {
+                                AllPass::new_freeverb(delay, spec.gain)
+                            } else {
+                                AllPass::new(delay, spec.gain)
+                            }
+                        }).collect(),
+                        modulators: Vec::new(),
+                    },
+                    ReverbStage::Combs { combs, gain, alternate_signs, delayed_output } => Stage::Combs {
+                        combs: combs.iter().map(|spec| {
+                            let delay = delay_samples(spec.delay);
+                            let decay_gain = || {
+                                let decay_samples = (decay * sample_rate).floor();
+                                10f64.powf(-(3.0 * delay as f64) / decay_samples)
+                            };
+                            let g = match spec.gain {
+                                CombGain::Decay => decay_gain(),
+                                CombGain::DampedDecay => decay_gain() * (1.0 - spec.damping),
+                                CombGain::Fixed(g) => g,
+                            };
+                            if spec.freeverb {
+                                CombFilter::new_one_pole_comb(delay, g, spec.damping)
+                            } else if spec.damping > 0.0 {
+                                CombFilter::new(delay, g, CombType::POSITIVE, true, spec.damping)
+                            } else {
+                                CombFilter::new_comb(delay, g, CombType::POSITIVE)
+                            }
+                        }).collect(),
+                        modulators: Vec::new(),
+                        gain: *gain,
+                        alternate_signs: *alternate_signs,
+                        delayed_output: *delayed_output,
+                    },
+                });
+            }
+            chains.push(stages);
+        }
 
         Self {
-            combs,
-            allpasses,
+            chains,
+            input_gain: topology.input_gain,
             decay,
-            reverb_type,
+            width: topology.width.clamp(0.0, 1.0),
+            // unity wet gain until the wet control is set
+            wet: if topology.wet_scale > 0.0 { 1.0 / topology.wet_scale } else { 0.0 },
+            dry: 0.0,
+            wet_scale: topology.wet_scale,
+            dry_scale: topology.dry_scale,
+            sample_rate,
+            pre_delay: None,
+            pre_delay_samples: 0,
+            early_reflections: None,
+            er_balance: 0.0,
+            mix: 1.0,
+            seed,
+        }
+    }
+
+    // delays the early reflections and the late reverb, pre_delay is in ms
+    pub fn with_pre_delay(mut self, pre_delay_ms: f64) -> Self {
+        self.pre_delay_samples = (pre_delay_ms.max(0.0) / 1000.0 * self.sample_rate) as usize;
+        if self.pre_delay_samples > MAX_DELAY {
+            println!("Pre-delay for Reverb too high. Setting to {} ms", MAX_DELAY as f64 * 1000.0 / self.sample_rate);
+            self.pre_delay_samples = MAX_DELAY;
+        }
+        self.pre_delay = if self.pre_delay_samples > 0 {
+            Some(Delay::new(self.pre_delay_samples, 0.0))
+        } else {
+            None
+        };
+        self
+    }
+
+    // balance is in [0, 1], 0.0 is only the late reverb, 1.0 only the early reflections
+    pub fn with_early_reflections(mut self, preset: RoomPreset, balance: f64) -> Self {
+        self.early_reflections = Some(EarlyReflections::new_preset(self.sample_rate, preset));
+        self.er_balance = balance.clamp(0.0, 1.0);
+        self
+    }
+
+    // mix is in [0, 1], 1.0 is fully wet
+    pub fn with_mix(mut self, mix: f64) -> Self {
+        self.mix = mix.clamp(0.0, 1.0);
+        self
+    }
+
+    // every comb and allpass delay wanders by up to depth_ms around its length, at about rate Hz.
+    // each line gets a random rate and phase from the reverb seed, which breaks up the metallic
+    // ringing of fixed delays. a depth of 0.0 turns the modulation off
+    pub fn with_modulation(mut self, depth_ms: f64, rate: f64) -> Self {
+        let mut rng = StdRng::seed_from_u64(self.seed.wrapping_add(1));
+        let sample_rate = self.sample_rate;
+        let enabled = depth_ms > 0.0;
+
+        for stage in self.chains.iter_mut().flatten() {
+            let (delays, modulators): (Vec<usize>, _) = match stage {
+                Stage::AllPasses { allpasses, modulators } => (allpasses.iter().map(|allpass| allpass.delay()).collect(), modulators),
+                Stage::Combs { combs, modulators, .. } => (combs.iter().map(|comb| comb.delay()).collect(), modulators),
+            };
+            *modulators = if enabled {
+                delays.iter().map(|delay| DelayModulator::new(sample_rate, *delay, depth_ms, rate, &mut rng)).collect()
+            } else {
+                Vec::new()
+            };
         }
+        self
     }
 
     pub fn new_comb_reverb(sample_rate: f64, decay: f64) -> Self {
-        Self::new(sample_rate, decay, ReverbType::CombReverb, 0.0)
+        Self::new(sample_rate, decay, ReverbType::CombReverb, 0.0, DEFAULT_SEED)
     }
 
     pub fn new_schroeder_reverb(sample_rate: f64, decay: f64) -> Self {
-        Self::new(sample_rate, decay, ReverbType::Schroeder, 0.0)
+        Self::new_schroeder_reverb_seeded(sample_rate, decay, DEFAULT_SEED)
+    }
+
+    // seed picks the random allpass delays
+    pub fn new_schroeder_reverb_seeded(sample_rate: f64, decay: f64, seed: u64) -> Self {
+        Self::new(sample_rate, decay, ReverbType::Schroeder, 0.0, seed)
     }
 
     pub fn new_lpf_comb(sample_rate: f64, decay: f64, damp: f64) -> Self {
-        Self::new(sample_rate, decay, ReverbType::LpfComb, damp)
+        Self::new(sample_rate, decay, ReverbType::LpfComb, damp, DEFAULT_SEED)
     }
 
     pub fn new_moorer_reverb(sample_rate: f64, decay: f64, damp: f64) -> Self {
-        Self::new(sample_rate, decay, ReverbType::Moorer, damp)
+        Self::new_moorer_reverb_seeded(sample_rate, decay, damp, DEFAULT_SEED)
     }
 
-    pub fn process_sample(&mut self, x: f64) -> f64 {
-        let mut y = 0.0;
-        match self.reverb_type {
-            ReverbType::CombReverb => {
-                for (i, comb) in self.combs.iter_mut().enumerate() {
-                    if i % 2 == 0 {
-                        y += comb.process_sample(x);
-                    } else {
-                        y -= comb.process_sample(x);
-                    }
-                }
-                y *= 0.25;
-            },
-            ReverbType::Schroeder => {
-                let mut after_allpasses: f64;
-                after_allpasses = self.allpasses[0].process_sample(x);
-                after_allpasses = self.allpasses[1].process_sample(after_allpasses);
-
-                for (i, comb) in self.combs.iter_mut().enumerate() {
-                    if i % 2 == 0 {
-                        y += comb.process_sample(x);
-                    } else {
-                        y -= comb.process_sample(x);
-                    }
-                }
+    // seed picks the random allpass delay
+    pub fn new_moorer_reverb_seeded(sample_rate: f64, decay: f64, damp: f64, seed: u64) -> Self {
+        Self::new(sample_rate, decay, ReverbType::Moorer, damp, seed)
+    }
 
-                y = self.allpasses[2].process_sample(y);
-                y = self.allpasses[3].process_sample(y);
-            },
-            ReverbType::LpfComb => {
-                for (i, comb) in self.combs.iter_mut().enumerate() {
-                    if i % 2 == 0 {
-                        y += comb.process_sample(x);
-                    } else {
-                        y -= comb.process_sample(x);
-                    }
-                }
-                y *= 0.8;
+    // room_size, damp, width, wet and dry are in [0, 1], like the freeverb controls
+    pub fn new_freeverb(sample_rate: f64, room_size: f64, damp: f64, width: f64, wet: f64, dry: f64) -> Self {
+        let mut reverb = Self::new(sample_rate, room_size, ReverbType::Freeverb, damp, DEFAULT_SEED);
+        reverb.width = width.clamp(0.0, 1.0);
+        reverb.wet = wet.clamp(0.0, 1.0);
+        reverb.dry = dry.clamp(0.0, 1.0);
+        reverb
+    }
+
+    pub fn process_sample_stereo(&mut self, x: f64) -> (f64, f64) {
+        let delayed = match &mut self.pre_delay {
+            // our delay line is one sample longer than the delay it is given
+            Some(delay) => delay.process_sample(x, self.pre_delay_samples - 1),
+            None => x,
+        };
+
+        let (late_l, late_r) = self.process_late_stereo(delayed);
+        let (wet_l, wet_r) = match &mut self.early_reflections {
+            Some(early_reflections) => {
+                let (er_l, er_r) = early_reflections.process_sample(delayed);
+                (
+                    self.er_balance * er_l + (1.0 - self.er_balance) * late_l,
+                    self.er_balance * er_r + (1.0 - self.er_balance) * late_r,
+                )
             },
-            ReverbType::Moorer => {
-                for (i, comb) in self.combs.iter_mut().enumerate() {
-                    if i % 2 == 0 {
-                        y += comb.process_sample(x);
-                    } else {
-                        y -= comb.process_sample(x);
-                    }
-                }
+            None => (late_l, late_r),
+        };
 
-                y *= 0.75;
+        (
+            self.mix * wet_l + (1.0 - self.mix) * x,
+            self.mix * wet_r + (1.0 - self.mix) * x,
+        )
+    }
 
-                y = self.allpasses[0].process_sample(y);
-            },
+    pub fn process_sample(&mut self, x: f64) -> f64 {
+        let (left, right) = self.process_sample_stereo(x);
+        0.5 * (left + right)
+    }
+
+    fn process_late_stereo(&mut self, x: f64) -> (f64, f64) {
+        let input = x * self.input_gain;
+        let (mut left, mut right) = (0.0, 0.0);
+        for (i, chain) in self.chains.iter_mut().enumerate() {
+            let y = chain.iter_mut().fold(input, |y, stage| stage.process_sample(y));
+            if i % 2 == 0 {
+                left += y;
+            } else {
+                right += y;
+            }
         }
-        y
+        let count = self.chains.len();
+        let left = left / count.div_ceil(2).max(1) as f64;
+        let right = if count > 1 { right / (count / 2) as f64 } else { left };
+
+        let wet = self.wet * self.wet_scale;
+        let wet1 = wet * (self.width / 2.0 + 0.5);
+        let wet2 = wet * ((1.0 - self.width) / 2.0);
+        let dry = self.dry * self.dry_scale;
+
+        (
+            left * wet1 + right * wet2 + x * dry,
+            right * wet1 + left * wet2 + x * dry,
+        )
     }
 }
 
@@ -220,6 +557,37 @@ impl AudioNode for Reverb {
     }
 }
 
+// a reverb with one output per chain, for stereo topologies like freeverb
+#[derive(Clone)]
+pub struct StereoReverb {
+    reverb: Reverb,
+}
+
+impl StereoReverb {
+    pub fn new(reverb: Reverb) -> Self {
+        Self { reverb }
+    }
+}
+
+impl AudioNode for StereoReverb {
+    const ID: u64 = 9975;
+    type Sample = f64;
+    type Inputs = U1;
+    type Outputs = U2;
+    type Setting = f64;
+
+    fn reset(&mut self) {
+    }
+
+    fn tick(
+        &mut self,
+        input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
+    ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
+        let (left, right) = self.reverb.process_sample_stereo(input[0]);
+        [left, right].into()
+    }
+}
+
 pub fn comb_reverb(sample_rate: f64, decay: f64) -> An<Reverb> {
     An(Reverb::new_comb_reverb(sample_rate, decay))
 }
@@ -228,10 +596,160 @@ pub fn schroeder_reverb(sample_rate: f64, decay: f64) -> An<Reverb> {
     An(Reverb::new_schroeder_reverb(sample_rate, decay))
 }
 
+pub fn schroeder_reverb_seeded(sample_rate: f64, decay: f64, seed: u64) -> An<Reverb> {
+    An(Reverb::new_schroeder_reverb_seeded(sample_rate, decay, seed))
+}
+
 pub fn lpf_comb_reverb(sample_rate: f64, decay: f64, damp: f64) -> An<Reverb> {
     An(Reverb::new_lpf_comb(sample_rate, decay, damp))
 }
 
 pub fn moorer_reverb(sample_rate: f64, decay: f64, damp: f64) -> An<Reverb> {
     An(Reverb::new_moorer_reverb(sample_rate, decay, damp))
-}
\ No newline at end of file
+}
+
+pub fn moorer_reverb_seeded(sample_rate: f64, decay: f64, damp: f64, seed: u64) -> An<Reverb> {
+    An(Reverb::new_moorer_reverb_seeded(sample_rate, decay, damp, seed))
+}
+/// created a new reverb of any type where:
+///- decay is in seconds (the room size in [0, 1] for freeverb)
+///- damp is in [0, 1]
+///- mod_depth is how far the delays wander in ms, 0.0 turns the modulation off
+///- mod_rate is in Hz, slow rates below 1Hz work best
+pub fn modulated_reverb(sample_rate: f64, reverb_type: ReverbType, decay: f64, damp: f64, mod_depth_ms: f64, mod_rate: f64) -> An<Reverb> {
+    An(Reverb::new(sample_rate, decay, reverb_type, damp, DEFAULT_SEED).with_modulation(mod_depth_ms, mod_rate))
+}
+
+/// created a new stereo freeverb where:
+///- room_size, damp, width, wet and dry are in [0, 1], like the freeverb controls
+pub fn freeverb(sample_rate: f64, room_size: f64, damp: f64, width: f64, wet: f64, dry: f64) -> An<StereoReverb> {
+    An(StereoReverb::new(Reverb::new_freeverb(sample_rate, room_size, damp, width, wet, dry)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(reverb: &mut Reverb) -> Vec<u64> {
+        let mut output = Vec::new();
+        for i in 0..44100 {
+            let x = if i % 4410 == 0 { 1.0 } else { 0.0 };
+            let (left, right) = reverb.process_sample_stereo(x);
+            output.push(left.to_bits());
+            output.push(right.to_bits());
+        }
+        output
+    }
+
+    #[test]
+    fn same_seed_renders_bit_for_bit() {
+        for reverb_type in [ReverbType::CombReverb, ReverbType::Schroeder, ReverbType::LpfComb, ReverbType::Moorer, ReverbType::Freeverb] {
+            let mut first = Reverb::new(44100.0, 1.5, reverb_type.clone(), 0.3, 7);
+            let mut second = Reverb::new(44100.0, 1.5, reverb_type, 0.3, 7);
+            assert_eq!(render(&mut first), render(&mut second));
+        }
+    }
+
+    #[test]
+    fn default_constructors_are_deterministic() {
+        assert_eq!(render(&mut Reverb::new_schroeder_reverb(44100.0, 2.0)), render(&mut Reverb::new_schroeder_reverb(44100.0, 2.0)));
+        assert_eq!(render(&mut Reverb::new_moorer_reverb(44100.0, 2.0, 0.3)), render(&mut Reverb::new_moorer_reverb(44100.0, 2.0, 0.3)));
+    }
+
+    #[test]
+    fn different_seeds_change_the_output() {
+        let mut first = Reverb::new(44100.0, 2.0, ReverbType::Schroeder, 0.0, 1);
+        let mut second = Reverb::new(44100.0, 2.0, ReverbType::Schroeder, 0.0, 2);
+        assert_ne!(render(&mut first), render(&mut second));
+        assert_ne!(render(&mut Reverb::new_schroeder_reverb_seeded(44100.0, 2.0, 1)), render(&mut Reverb::new_schroeder_reverb(44100.0, 2.0)));
+        assert_ne!(render(&mut Reverb::new_moorer_reverb_seeded(44100.0, 2.0, 0.3, 1)), render(&mut Reverb::new_moorer_reverb(44100.0, 2.0, 0.3)));
+    }
+
+    #[test]
+    fn modulation_is_seeded_and_changes_the_output() {
+        for reverb_type in [ReverbType::CombReverb, ReverbType::Schroeder, ReverbType::LpfComb, ReverbType::Moorer, ReverbType::Freeverb] {
+            let mut first = Reverb::new(44100.0, 1.5, reverb_type.clone(), 0.3, 7).with_modulation(0.5, 0.3);
+            let mut second = Reverb::new(44100.0, 1.5, reverb_type.clone(), 0.3, 7).with_modulation(0.5, 0.3);
+            let mut fixed = Reverb::new(44100.0, 1.5, reverb_type, 0.3, 7);
+            let modulated = render(&mut first);
+            assert_eq!(modulated, render(&mut second));
+            assert_ne!(modulated, render(&mut fixed));
+        }
+    }
+
+    #[test]
+    fn topologies_bring_their_own_output_mix() {
+        let left_channel = |topology: &ReverbTopology| -> Vec<u64> {
+            render(&mut Reverb::from_topology(44100.0, 1.5, topology, 0)).into_iter().step_by(2).collect()
+        };
+        let freeverb = ReverbType::Freeverb.topology(0.5, 0.5);
+
+        // two chains without freeverb's wet scale and width, the left chain comes out as it is
+        let plain = ReverbTopology { chains: freeverb.chains.clone(), ..Default::default() };
+        let left_only = ReverbTopology { chains: vec![freeverb.chains[0].clone()], ..Default::default() };
+        assert_eq!(left_channel(&plain), left_channel(&left_only));
+
+        // a third chain is heard on the left
+        let mut three = plain.clone();
+        three.chains.push(ReverbType::Schroeder.topology(1.5, 0.0).chains[0].clone());
+        assert_ne!(left_channel(&three), left_channel(&plain));
+    }
+
+    // jezar's revmodel, written the way the original c++ does it
+    struct ReferenceFreeverb {
+        combs: Vec<(Vec<f64>, usize, f64)>,
+        allpasses: Vec<(Vec<f64>, usize)>,
+    }
+
+    impl ReferenceFreeverb {
+        fn new(spread: usize) -> Self {
+            Self {
+                combs: FREEVERB_COMBS.iter().map(|length| (vec![0.0; length + spread], 0, 0.0)).collect(),
+                allpasses: FREEVERB_ALLPASSES.iter().map(|length| (vec![0.0; length + spread], 0)).collect(),
+            }
+        }
+
+        fn process(&mut self, input: f64, feedback: f64, damp: f64) -> f64 {
+            let mut out = 0.0;
+            for (buffer, index, filterstore) in self.combs.iter_mut() {
+                let output = buffer[*index];
+                *filterstore = output * (1.0 - damp) + *filterstore * damp;
+                buffer[*index] = input + *filterstore * feedback;
+                *index = (*index + 1) % buffer.len();
+                out += output;
+            }
+            for (buffer, index) in self.allpasses.iter_mut() {
+                let bufout = buffer[*index];
+                buffer[*index] = out + bufout * FREEVERB_ALLPASS_GAIN;
+                out = bufout - out;
+                *index = (*index + 1) % buffer.len();
+            }
+            out
+        }
+    }
+
+    #[test]
+    fn freeverb_matches_the_reference() {
+        let (room_size, damp, width, wet, dry) = (0.6, 0.4, 0.7, 0.5, 0.2);
+        let feedback = room_size * FREEVERB_SCALE_ROOM + FREEVERB_OFFSET_ROOM;
+        let damp_coefficient = damp * FREEVERB_SCALE_DAMP;
+        let wet1 = wet * FREEVERB_SCALE_WET * (width / 2.0 + 0.5);
+        let wet2 = wet * FREEVERB_SCALE_WET * (1.0 - width) / 2.0;
+
+        let mut reverb = Reverb::new_freeverb(44100.0, room_size, damp, width, wet, dry);
+        let mut left = ReferenceFreeverb::new(0);
+        let mut right = ReferenceFreeverb::new(FREEVERB_STEREO_SPREAD);
+        for i in 0..20000 {
+            let x = if i % 5000 == 0 { 1.0 } else { 0.1 * (i as f64 * 0.05).sin() };
+            // the same mono signal on both inputs
+            let input = (x + x) * FREEVERB_FIXED_GAIN;
+            let out_l = left.process(input, feedback, damp_coefficient);
+            let out_r = right.process(input, feedback, damp_coefficient);
+            let expected_l = out_l * wet1 + out_r * wet2 + x * dry * FREEVERB_SCALE_DRY;
+            let expected_r = out_r * wet1 + out_l * wet2 + x * dry * FREEVERB_SCALE_DRY;
+
+            let (l, r) = reverb.process_sample_stereo(x);
+            assert!((l - expected_l).abs() < 1e-9 && (r - expected_r).abs() < 1e-9, "sample {}: ({}, {}) against ({}, {})", i, l, r, expected_l, expected_r);
+        }
+    }
+}
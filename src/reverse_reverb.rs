@@ -0,0 +1,96 @@
+use fundsp::{audionode::AudioNode, prelude::An};
+use numeric_array::typenum::{self};
+
+use crate::convolution::{ConvolutionReverb, ImpulseResponse};
+use crate::delay::{Delay, MAX_DELAY};
+use crate::reverb::{Reverb, ReverbType};
+use crate::seed::DEFAULT_SEED;
+
+const BLOCK_SIZE: usize = 256;
+// fade at the end of the captured tail, so the reversed swell doesn't start with a click
+const FADE_MS: f64 = 5.0;
+
+// the reverb's impulse response is captured, reversed and convolved with the input.
+// the dry signal is delayed by the length of the tail, so the reversed tail swells
+// up right before the note it belongs to
+#[derive(Clone)]
+pub struct ReverseReverb {
+    convolver: ConvolutionReverb,
+    dry: Delay,
+    length: usize,
+    mix: f64,
+}
+
+impl ReverseReverb {
+    // the reverb should be fully wet, the mix is applied here. length is in ms
+    pub fn new(sample_rate: f64, mut reverb: Reverb, length_ms: f64, mix: f64) -> Self {
+        let mut length = ((length_ms / 1000.0 * sample_rate) as usize).max(1);
+        if length > MAX_DELAY {
+            println!("Length for Reverse Reverb too high. Setting to {} ms", MAX_DELAY as f64 * 1000.0 / sample_rate);
+            length = MAX_DELAY;
+        }
+        let fade = ((FADE_MS / 1000.0 * sample_rate) as usize).clamp(1, length);
+
+        let mut left = Vec::with_capacity(length);
+        let mut right = Vec::with_capacity(length);
+        for i in 0..length {
+            let (l, r) = reverb.process_sample_stereo(if i == 0 { 1.0 } else { 0.0 });
+            let gain = ((length - i) as f64 / fade as f64).min(1.0);
+            left.push(gain * l);
+            right.push(gain * r);
+        }
+        left.reverse();
+        right.reverse();
+
+        let response = ImpulseResponse::new(sample_rate, vec![left, right]);
+
+        Self {
+            convolver: ConvolutionReverb::new(sample_rate, &response, BLOCK_SIZE, true, 0.0),
+            dry: Delay::new(length, 0.0),
+            length,
+            mix: mix.clamp(0.0, 1.0),
+        }
+    }
+
+    pub fn process_sample(&mut self, x: f64) -> (f64, f64) {
+        let (left, right) = self.convolver.process_sample(x, x);
+        // our delay line is one sample longer than the delay it is given
+        let dry = self.dry.process_sample(x, self.length - 1);
+        (
+            self.mix * left + (1.0 - self.mix) * dry,
+            self.mix * right + (1.0 - self.mix) * dry,
+        )
+    }
+}
+
+impl AudioNode for ReverseReverb {
+    const ID: u64 = 9983;
+    type Sample = f64;
+    type Inputs = typenum::U1;
+    type Outputs = typenum::U2;
+    type Setting = f64;
+
+    fn reset(&mut self) {
+    }
+
+    fn tick(
+        &mut self,
+        input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
+    ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
+        let (left, right) = self.process_sample(input[0]);
+        [left, right].into()
+    }
+
+    fn latency(&mut self) -> Option<f64> {
+        Some(self.length as f64)
+    }
+}
+
+/// created a new reverse reverb where:
+///- decay is in seconds
+///- length is the part of the tail that gets reversed in ms, the output is delayed by it
+///- mix is in [0, 1], 1.0 is fully wet
+pub fn my_reverse_reverb(sample_rate: f64, reverb_type: ReverbType, decay: f64, damp: f64, length_ms: f64, mix: f64) -> An<ReverseReverb> {
+    let reverb = Reverb::new(sample_rate, decay, reverb_type, damp, DEFAULT_SEED);
+    An(ReverseReverb::new(sample_rate, reverb, length_ms, mix))
+}
@@ -0,0 +1,2 @@
+// seed used when none is given, so renders of the same patch are identical
+pub const DEFAULT_SEED: u64 = 0;
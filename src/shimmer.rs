@@ -0,0 +1,113 @@
+use fundsp::{audionode::AudioNode, prelude::An};
+use numeric_array::typenum::{self};
+
+use crate::fdn::{FdnMatrix, FdnReverb};
+use crate::filter::{BiquadFilter, first_order_hpf_coefficients, first_order_lpf_coefficients};
+use crate::pitch_shifter::PitchShifter;
+use crate::reverb::Reverb;
+
+const SHIFTER_WINDOW_MS: f64 = 60.0;
+// the shifted signal is kept between these frequencies so the shimmer doesn't turn to mud or fizz
+const FEEDBACK_HPF: f64 = 300.0;
+const FEEDBACK_LPF: f64 = 8000.0;
+
+#[derive(Clone, Copy)]
+pub enum ShimmerCore {
+    Fdn,
+    Moorer,
+}
+
+#[derive(Clone)]
+enum Core {
+    Fdn(FdnReverb),
+    Moorer(Box<Reverb>),
+}
+
+impl Core {
+    fn process_sample(&mut self, x: f64) -> (f64, f64) {
+        match self {
+            Core::Fdn(reverb) => reverb.process_sample(x),
+            Core::Moorer(reverb) => reverb.process_sample_stereo(x),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Shimmer {
+    core: Core,
+    octave: PitchShifter,
+    fifth: PitchShifter,
+    hpf: BiquadFilter,
+    lpf: BiquadFilter,
+    shimmer: f64,
+    fifth_mix: f64,
+    mix: f64,
+    feedback: f64,
+}
+
+impl Shimmer {
+    pub fn new(sample_rate: f64, core: ShimmerCore, decay: f64, damp: f64, shimmer: f64, fifth_mix: f64, mix: f64) -> Self {
+        let damp = damp.clamp(0.0, 1.0);
+        let core = match core {
+            // damping shortens the high band of the fdn
+            ShimmerCore::Fdn => Core::Fdn(FdnReverb::new(sample_rate, 8, FdnMatrix::HADAMARD, decay, decay, decay * (1.0 - 0.8 * damp), 0.3, 0.4)),
+            ShimmerCore::Moorer => Core::Moorer(Box::new(Reverb::new_moorer_reverb(sample_rate, decay, damp))),
+        };
+
+        Self {
+            core,
+            octave: PitchShifter::new(sample_rate, 12.0, SHIFTER_WINDOW_MS),
+            fifth: PitchShifter::new(sample_rate, 7.0, SHIFTER_WINDOW_MS),
+            hpf: BiquadFilter::new(first_order_hpf_coefficients(sample_rate, FEEDBACK_HPF)),
+            lpf: BiquadFilter::new(first_order_lpf_coefficients(sample_rate, FEEDBACK_LPF)),
+            shimmer: shimmer.clamp(0.0, 0.95),
+            fifth_mix: fifth_mix.clamp(0.0, 1.0),
+            mix: mix.clamp(0.0, 1.0),
+            feedback: 0.0,
+        }
+    }
+
+    pub fn process_sample(&mut self, x: f64) -> (f64, f64) {
+        let (left, right) = self.core.process_sample(x + self.shimmer * self.feedback);
+
+        // +12 and +7 semitones, filtered and soft clipped so the loop can't run away
+        let tail = 0.5 * (left + right);
+        let shifted = (1.0 - self.fifth_mix) * self.octave.process_sample(tail) + self.fifth_mix * self.fifth.process_sample(tail);
+        self.feedback = self.lpf.process_sample(self.hpf.process_sample(shifted)).tanh();
+
+        (
+            self.mix * left + (1.0 - self.mix) * x,
+            self.mix * right + (1.0 - self.mix) * x,
+        )
+    }
+}
+
+impl AudioNode for Shimmer {
+    const ID: u64 = 9986;
+    type Sample = f64;
+    type Inputs = typenum::U1;
+    type Outputs = typenum::U2;
+    type Setting = f64;
+
+    fn reset(&mut self) {
+    }
+
+    fn tick(
+        &mut self,
+        input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
+    ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
+        let (left, right) = self.process_sample(input[0]);
+        [left, right].into()
+    }
+}
+
+/// created a new shimmer reverb where:
+///- decay is in seconds
+///- damp is in [0, 1]
+///- shimmer is the amount of pitch shifted feedback in [0, 0.95]
+///- fifth_mix blends the +12 shift (0.0) with the +7 shift (1.0)
+///- mix is in [0, 1], 1.0 is fully wet
+#[allow(clippy::too_many_arguments)]
+pub fn my_shimmer_reverb(sample_rate: f64, core: ShimmerCore, decay: f64, damp: f64, shimmer: f64, fifth_mix: f64, mix: f64) -> An<Shimmer> {
+    An(Shimmer::new(sample_rate, core, decay, damp, shimmer, fifth_mix, mix))
+}
@@ -0,0 +1,308 @@
+use std::f64::consts::PI;
+
+use fundsp::{audionode::AudioNode, prelude::An};
+use numeric_array::typenum::{self};
+use rustfft::num_complex::Complex;
+
+// log spaced frequencies searched for the peak gain
+const PEAK_SEARCH_POINTS: usize = 200;
+
+// the passive treble/mid/bass network found in most guitar amps
+#[derive(Clone, Copy)]
+pub enum ToneStackType {
+    // '59 Bassman
+    Fender,
+    // JCM800
+    Marshall,
+    // the AC30 top boost approximated with the same network. it has no mid pot,
+    // r3 is a fixed resistor and the mid knob does nothing
+    Vox,
+}
+
+// treble pot r1, bass pot r2, mid pot r3, slope resistor r4, in ohms and farads
+#[derive(Clone, Copy)]
+pub struct ToneStackComponents {
+    pub r1: f64,
+    pub r2: f64,
+    pub r3: f64,
+    pub r4: f64,
+    pub c1: f64,
+    pub c2: f64,
+    pub c3: f64,
+    // false when r3 is a fixed resistor instead of a pot
+    pub mid_pot: bool,
+}
+
+impl ToneStackComponents {
+    pub fn from_type(stack_type: ToneStackType) -> Self {
+        match stack_type {
+            ToneStackType::Fender => Self {
+                r1: 250e3,
+                r2: 1e6,
+                r3: 25e3,
+                r4: 56e3,
+                c1: 250e-12,
+                c2: 20e-9,
+                c3: 20e-9,
+                mid_pot: true,
+            },
+            ToneStackType::Marshall => Self {
+                r1: 220e3,
+                r2: 1e6,
+                r3: 22e3,
+                r4: 33e3,
+                c1: 470e-12,
+                c2: 22e-9,
+                c3: 22e-9,
+                mid_pot: true,
+            },
+            ToneStackType::Vox => Self {
+                r1: 1e6,
+                r2: 1e6,
+                r3: 10e3,
+                r4: 100e3,
+                c1: 50e-12,
+                c2: 22e-9,
+                c3: 22e-9,
+                mid_pot: false,
+            },
+        }
+    }
+
+    // analog transfer function (b, a) in ascending powers of s, after yeh and smith's
+    // bassman analysis. t, m and l are the treble, mid and bass pot positions in [0, 1],
+    // without a mid pot all of r3 is in the circuit
+    pub fn analog_coefficients(&self, t: f64, m: f64, l: f64) -> ([f64; 4], [f64; 4]) {
+        let ToneStackComponents { r1, r2, r3, r4, c1, c2, c3, mid_pot } = *self;
+        let m = if mid_pot { m } else { 1.0 };
+
+        let b1 = t * c1 * r1 + m * c3 * r3 + l * (c1 * r2 + c2 * r2) + (c1 * r3 + c2 * r3);
+        let b2 = t * (c1 * c2 * r1 * r4 + c1 * c3 * r1 * r4)
+            - m * m * (c1 * c3 * r3 * r3 + c2 * c3 * r3 * r3)
+            + m * (c1 * c3 * r1 * r3 + c1 * c3 * r3 * r3 + c2 * c3 * r3 * r3)
+            + l * (c1 * c2 * r1 * r2 + c1 * c2 * r2 * r4 + c1 * c3 * r2 * r4)
+            + l * m * (c1 * c3 * r2 * r3 + c2 * c3 * r2 * r3)
+            + (c1 * c2 * r1 * r3 + c1 * c2 * r3 * r4 + c1 * c3 * r3 * r4);
+        let b3 = l * m * (c1 * c2 * c3 * r1 * r2 * r3 + c1 * c2 * c3 * r2 * r3 * r4)
+            - m * m * (c1 * c2 * c3 * r1 * r3 * r3 + c1 * c2 * c3 * r3 * r3 * r4)
+            + m * (c1 * c2 * c3 * r1 * r3 * r3 + c1 * c2 * c3 * r3 * r3 * r4)
+            + t * c1 * c2 * c3 * r1 * r3 * r4
+            - t * m * c1 * c2 * c3 * r1 * r3 * r4
+            + t * l * c1 * c2 * c3 * r1 * r2 * r4;
+
+        let a1 = (c1 * r1 + c1 * r3 + c2 * r3 + c2 * r4 + c3 * r4) + m * c3 * r3 + l * (c1 * r2 + c2 * r2);
+        let a2 = m * (c1 * c3 * r1 * r3 - c2 * c3 * r3 * r4 + c1 * c3 * r3 * r3 + c2 * c3 * r3 * r3)
+            + l * m * (c1 * c3 * r2 * r3 + c2 * c3 * r2 * r3)
+            - m * m * (c1 * c3 * r3 * r3 + c2 * c3 * r3 * r3)
+            + l * (c1 * c2 * r2 * r4 + c1 * c2 * r1 * r2 + c1 * c3 * r2 * r4 + c2 * c3 * r2 * r4)
+            + (c1 * c2 * r1 * r4 + c1 * c3 * r1 * r4 + c1 * c2 * r3 * r4
+                + c1 * c2 * r1 * r3 + c1 * c3 * r3 * r4 + c2 * c3 * r3 * r4);
+        let a3 = l * m * (c1 * c2 * c3 * r1 * r2 * r3 + c1 * c2 * c3 * r2 * r3 * r4)
+            - m * m * (c1 * c2 * c3 * r1 * r3 * r3 + c1 * c2 * c3 * r3 * r3 * r4)
+            + m * (c1 * c2 * c3 * r3 * r3 * r4 + c1 * c2 * c3 * r1 * r3 * r3 - c1 * c2 * c3 * r1 * r3 * r4)
+            + l * c1 * c2 * c3 * r1 * r2 * r4
+            + c1 * c2 * c3 * r1 * r3 * r4;
+
+        ([0.0, b1, b2, b3], [1.0, a1, a2, a3])
+    }
+}
+
+// bilinear transform of a third order analog filter, (b, a) in ascending powers of z^-1
+// with a[0] == 1.0. every s^k becomes c^k (1 - z^-1)^k (1 + z^-1)^(3 - k), c = 2 * fs
+pub fn bilinear_third_order(b: [f64; 4], a: [f64; 4], sample_rate: f64) -> ([f64; 4], [f64; 4]) {
+    const TERMS: [[f64; 4]; 4] = [
+        [1.0, 3.0, 3.0, 1.0],
+        [1.0, 1.0, -1.0, -1.0],
+        [1.0, -1.0, -1.0, 1.0],
+        [1.0, -3.0, 3.0, -1.0],
+    ];
+    let c = 2.0 * sample_rate;
+
+    let mut digital_b = [0.0; 4];
+    let mut digital_a = [0.0; 4];
+    for k in 0..4 {
+        let scale = c.powi(k as i32);
+        for i in 0..4 {
+            digital_b[i] += b[k] * scale * TERMS[k][i];
+            digital_a[i] += a[k] * scale * TERMS[k][i];
+        }
+    }
+    let a0 = digital_a[0];
+    digital_b.iter_mut().chain(digital_a.iter_mut()).for_each(|x| *x /= a0);
+    (digital_b, digital_a)
+}
+
+// the knobs interact like on the real amp, the mid pot changes where bass and treble sit.
+// the network is passive, so the output is 5 to 20dB quieter than the input
+#[derive(Clone)]
+pub struct ToneStack {
+    components: ToneStackComponents,
+    sample_rate: f64,
+    bass: f64,
+    mid: f64,
+    treble: f64,
+    b: [f64; 4],
+    a: [f64; 4],
+    // transposed direct form II state
+    z: [f64; 3],
+}
+
+impl ToneStack {
+    // knobs are in [0, 1]
+    pub fn new(sample_rate: f64, stack_type: ToneStackType, bass: f64, mid: f64, treble: f64) -> Self {
+        Self::from_components(sample_rate, ToneStackComponents::from_type(stack_type), bass, mid, treble)
+    }
+
+    pub fn from_components(sample_rate: f64, components: ToneStackComponents, bass: f64, mid: f64, treble: f64) -> Self {
+        let mut stack = Self {
+            components,
+            sample_rate,
+            bass: 0.0,
+            mid: 0.0,
+            treble: 0.0,
+            b: [0.0; 4],
+            a: [1.0, 0.0, 0.0, 0.0],
+            z: [0.0; 3],
+        };
+        stack.set_controls(bass, mid, treble);
+        stack
+    }
+
+    pub fn set_controls(&mut self, bass: f64, mid: f64, treble: f64) {
+        self.bass = bass.clamp(0.0, 1.0);
+        self.mid = mid.clamp(0.0, 1.0);
+        self.treble = treble.clamp(0.0, 1.0);
+        self.update_coefficients();
+    }
+
+    fn update_coefficients(&mut self) {
+        // the bass pot is audio taper, the other two are linear
+        let l = (100f64.powf(self.bass) - 1.0) / 99.0;
+        let (b, a) = self.components.analog_coefficients(self.treble, self.mid, l);
+        (self.b, self.a) = bilinear_third_order(b, a, self.sample_rate);
+    }
+
+    // magnitude of the response at frequency Hz
+    pub fn gain(&self, frequency: f64) -> f64 {
+        let z1 = Complex::from_polar(1.0, -2.0 * PI * frequency / self.sample_rate);
+        let polynomial = |coefficients: &[f64; 4]| coefficients.iter().rev().fold(Complex::new(0.0, 0.0), |acc, c| acc * z1 + c);
+        (polynomial(&self.b) / polynomial(&self.a)).norm()
+    }
+
+    // loudest gain between 20Hz and 20kHz, or the nyquist frequency
+    pub fn peak_gain(&self) -> f64 {
+        let top = (20000.0f64).min(0.5 * self.sample_rate);
+        (0..=PEAK_SEARCH_POINTS)
+            .map(|i| 20.0 * (top / 20.0).powf(i as f64 / PEAK_SEARCH_POINTS as f64))
+            .map(|frequency| self.gain(frequency))
+            .fold(0.0, f64::max)
+    }
+
+    pub fn process_sample(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[1] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[2] * y + self.z[2];
+        self.z[2] = self.b[3] * x - self.a[3] * y;
+        y
+    }
+}
+
+impl AudioNode for ToneStack {
+    const ID: u64 = 9976;
+    type Sample = f64;
+    type Inputs = typenum::U1;
+    type Outputs = typenum::U1;
+    type Setting = f64;
+
+    fn reset(&mut self) {
+        self.z = [0.0; 3];
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.update_coefficients();
+    }
+
+    fn tick(
+        &mut self,
+        input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
+    ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
+        let y = self.process_sample(input[0]);
+        [y].into()
+    }
+}
+
+/// created a new tone stack where:
+///- stack_type picks the component values of a Fender, Marshall or Vox network
+///- bass, mid and treble are the knobs in [0, 1], 0.5 is noon. the Vox has no mid pot, mid does nothing there
+pub fn my_tone_stack(sample_rate: f64, stack_type: ToneStackType, bass: f64, mid: f64, treble: f64) -> An<ToneStack> {
+    An(ToneStack::new(sample_rate, stack_type, bass, mid, treble))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn polynomial(coefficients: &[f64; 4], x: Complex<f64>) -> Complex<f64> {
+        coefficients.iter().rev().fold(Complex::new(0.0, 0.0), |acc, c| acc * x + c)
+    }
+
+    fn digital_db(stack: &ToneStack, frequency: f64) -> f64 {
+        let z1 = Complex::from_polar(1.0, -2.0 * PI * frequency / stack.sample_rate);
+        20.0 * (polynomial(&stack.b, z1) / polynomial(&stack.a, z1)).norm().log10()
+    }
+
+    #[test]
+    fn digital_response_follows_the_circuit() {
+        for stack_type in [ToneStackType::Fender, ToneStackType::Marshall, ToneStackType::Vox] {
+            let stack = ToneStack::new(48000.0, stack_type, 0.3, 0.6, 0.8);
+            let l = (100f64.powf(0.3) - 1.0) / 99.0;
+            let (b, a) = stack.components.analog_coefficients(0.8, 0.6, l);
+            for frequency in [40.0, 100.0, 300.0, 700.0, 1500.0] {
+                let s = Complex::new(0.0, 2.0 * PI * frequency);
+                let analog = 20.0 * (polynomial(&b, s) / polynomial(&a, s)).norm().log10();
+                let digital = digital_db(&stack, frequency);
+                assert!((analog - digital).abs() < 0.2, "{} against {} at {}Hz", digital, analog, frequency);
+            }
+        }
+    }
+
+    #[test]
+    fn mid_knob_fills_the_scoop() {
+        let scooped = ToneStack::new(44100.0, ToneStackType::Fender, 0.5, 0.0, 0.5);
+        let full = ToneStack::new(44100.0, ToneStackType::Fender, 0.5, 1.0, 0.5);
+        assert!(digital_db(&full, 1000.0) > digital_db(&scooped, 1000.0) + 8.0);
+
+        // and the filter that runs is the one that was analysed
+        let mut stack = full.clone();
+        let length = 44100;
+        let (re, im) = (0..length).fold((0.0, 0.0), |(re, im), i| {
+            let phase = 2.0 * PI * 1000.0 * i as f64 / 44100.0;
+            let y = stack.process_sample(phase.sin());
+            if i < length / 2 {
+                return (re, im);
+            }
+            (re + y * phase.cos(), im + y * phase.sin())
+        });
+        let measured = 20.0 * (4.0 * (re * re + im * im).sqrt() / length as f64).log10();
+        assert!((measured - digital_db(&full, 1000.0)).abs() < 0.1);
+    }
+
+    #[test]
+    fn vox_has_no_mid_knob() {
+        let scooped = ToneStack::new(44100.0, ToneStackType::Vox, 0.5, 0.0, 0.5);
+        let full = ToneStack::new(44100.0, ToneStackType::Vox, 0.5, 1.0, 0.5);
+        assert_eq!((scooped.b, scooped.a), (full.b, full.a));
+    }
+
+    #[test]
+    fn peak_gain_is_the_loudest_point_of_the_response() {
+        let stack = ToneStack::new(44100.0, ToneStackType::Marshall, 0.5, 0.5, 0.5);
+        let peak = stack.peak_gain();
+        assert!(peak < 1.0);
+        for frequency in [50.0, 200.0, 1000.0, 5000.0, 15000.0] {
+            assert!(stack.gain(frequency) <= peak);
+            assert!((20.0 * stack.gain(frequency).log10() - digital_db(&stack, frequency)).abs() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use std::sync::Arc;
+
+use fundsp::{audionode::AudioNode, prelude::An};
+use numeric_array::typenum::{self};
+
+use crate::oversampling::{DEFAULT_OVERSAMPLING, Oversampler};
+
+// a user defined waveshaping curve
+#[derive(Clone)]
+pub enum TransferCurve {
+    // evenly spaced over [-1, 1], read with linear interpolation and held outside
+    Table(Vec<f64>),
+    // monotone cubic through (x, y) control points sorted by x, held outside
+    // the first and last point. built with TransferCurve::spline
+    Spline {
+        points: Vec<(f64, f64)>,
+        tangents: Vec<f64>,
+    },
+    Closure(Arc<dyn Fn(f64) -> f64 + Send + Sync>),
+    // amplitudes of the harmonics from the fundamental up, see TransferCurve::chebyshev
+    Chebyshev(Vec<f64>),
+}
+
+impl TransferCurve {
+    pub fn table(values: Vec<f64>) -> Self {
+        TransferCurve::Table(values)
+    }
+
+    // samples any function over [-1, 1] into a table of size points
+    pub fn sampled(size: usize, f: impl Fn(f64) -> f64) -> Self {
+        let size = size.max(2);
+        TransferCurve::Table((0..size).map(|i| f(2.0 * i as f64 / (size - 1) as f64 - 1.0)).collect())
+    }
+
+    // fritsch-carlson tangents, so the curve never overshoots between the points
+    pub fn spline(mut points: Vec<(f64, f64)>) -> Self {
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        points.dedup_by(|a, b| a.0 == b.0);
+
+        let slopes: Vec<f64> = points.windows(2)
+            .map(|pair| (pair[1].1 - pair[0].1) / (pair[1].0 - pair[0].0))
+            .collect();
+        let mut tangents: Vec<f64> = (0..points.len())
+            .map(|i| match (i.checked_sub(1).and_then(|j| slopes.get(j)), slopes.get(i)) {
+                (Some(left), Some(right)) if left * right > 0.0 => 0.5 * (left + right),
+                (Some(_), Some(_)) => 0.0,
+                (Some(slope), None) | (None, Some(slope)) => *slope,
+                (None, None) => 0.0,
+            })
+            .collect();
+        for (i, slope) in slopes.iter().enumerate() {
+            if *slope == 0.0 {
+                continue;
+            }
+            let a = tangents[i] / slope;
+            let b = tangents[i + 1] / slope;
+            let length = (a * a + b * b).sqrt();
+            if length > 3.0 {
+                tangents[i] = 3.0 * a / length * slope;
+                tangents[i + 1] = 3.0 * b / length * slope;
+            }
+        }
+
+        TransferCurve::Spline {
+            points,
+            tangents,
+        }
+    }
+
+    pub fn closure(f: impl Fn(f64) -> f64 + Send + Sync + 'static) -> Self {
+        TransferCurve::Closure(Arc::new(f))
+    }
+
+    // sum of chebyshev polynomials, amplitudes[0] is the fundamental. a full scale sine comes
+    // out with exactly these harmonic amplitudes. the input is clipped to [-1, 1] where the
+    // polynomials stay bounded, and the curve is shifted so silence stays silent
+    pub fn chebyshev(amplitudes: Vec<f64>) -> Self {
+        TransferCurve::Chebyshev(amplitudes)
+    }
+
+    pub fn apply(&self, x: f64) -> f64 {
+        match self {
+            TransferCurve::Table(values) => {
+                if values.is_empty() {
+                    return 0.0;
+                }
+                let position = ((x.clamp(-1.0, 1.0) + 1.0) * 0.5 * (values.len() - 1) as f64).max(0.0);
+                let index = (position.floor() as usize).min(values.len() - 1);
+                let next = (index + 1).min(values.len() - 1);
+                let frac = position - index as f64;
+                values[index] + (values[next] - values[index]) * frac
+            },
+            TransferCurve::Spline { points, tangents } => {
+                let (first, last) = match (points.first(), points.last()) {
+                    (Some(first), Some(last)) => (first, last),
+                    _ => return 0.0,
+                };
+                if x <= first.0 {
+                    return first.1;
+                }
+                if x >= last.0 {
+                    return last.1;
+                }
+                let i = points.partition_point(|point| point.0 <= x) - 1;
+                let ((x0, y0), (x1, y1)) = (points[i], points[i + 1]);
+                let h = x1 - x0;
+                let t = (x - x0) / h;
+                // cubic hermite basis
+                let h00 = (1.0 + 2.0 * t) * (1.0 - t) * (1.0 - t);
+                let h10 = t * (1.0 - t) * (1.0 - t);
+                let h01 = t * t * (3.0 - 2.0 * t);
+                let h11 = t * t * (t - 1.0);
+                h00 * y0 + h10 * h * tangents[i] + h01 * y1 + h11 * h * tangents[i + 1]
+            },
+            TransferCurve::Closure(f) => f(x),
+            TransferCurve::Chebyshev(amplitudes) => {
+                chebyshev_sum(amplitudes, x.clamp(-1.0, 1.0)) - chebyshev_sum(amplitudes, 0.0)
+            },
+        }
+    }
+}
+
+// clenshaw's recurrence for sum(a[k] * T(k + 1, x))
+fn chebyshev_sum(amplitudes: &[f64], x: f64) -> f64 {
+    let mut b1 = 0.0;
+    let mut b2 = 0.0;
+    for a in amplitudes.iter().rev() {
+        let b0 = a + 2.0 * x * b1 - b2;
+        b2 = b1;
+        b1 = b0;
+    }
+    // the T(0) coefficient is zero
+    x * b1 - b2
+}
+
+#[derive(Clone)]
+pub struct CustomWaveshaper {
+    curve: TransferCurve,
+    pre_gain: f64,
+    post_gain: f64,
+}
+
+impl CustomWaveshaper {
+    pub fn new(curve: TransferCurve, pre_gain: f64, post_gain: f64) -> Self {
+        Self {
+            curve,
+            pre_gain: pre_gain.max(0.0),
+            post_gain: post_gain.max(0.0),
+        }
+    }
+
+    pub fn process_sample(&mut self, x: f64) -> f64 {
+        self.curve.apply(x * self.pre_gain) * self.post_gain
+    }
+}
+
+impl AudioNode for CustomWaveshaper {
+    const ID: u64 = 9978;
+    type Sample = f64;
+    type Inputs = typenum::U1;
+    type Outputs = typenum::U1;
+    type Setting = f64;
+
+    fn reset(&mut self) {}
+
+    fn tick(
+        &mut self,
+        input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
+    ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
+        let y = self.process_sample(input[0]);
+        [y].into()
+    }
+}
+
+/// created a new waveshaper with a custom curve where:
+///- curve is a table, spline, closure or chebyshev harmonic list
+///- it runs oversampled by DEFAULT_OVERSAMPLING like the other waveshapers
+pub fn my_custom_waveshaper(curve: TransferCurve, pre_gain: f64, post_gain: f64) -> An<Oversampler<CustomWaveshaper>> {
+    An(Oversampler::new(DEFAULT_OVERSAMPLING, CustomWaveshaper::new(curve, pre_gain, post_gain)))
+}
+
+/// same as my_custom_waveshaper, with a curve generated from harmonic amplitudes
+pub fn my_harmonic_waveshaper(amplitudes: Vec<f64>, pre_gain: f64, post_gain: f64) -> An<Oversampler<CustomWaveshaper>> {
+    my_custom_waveshaper(TransferCurve::chebyshev(amplitudes), pre_gain, post_gain)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+
+    #[test]
+    fn chebyshev_curve_gives_the_requested_harmonics() {
+        let amplitudes = vec![1.0, 0.5, 0.0, 0.25, 0.1];
+        let curve = TransferCurve::chebyshev(amplitudes.clone());
+        // one period of a full scale cosine, correlated with each harmonic
+        let length = 1024;
+        let output: Vec<f64> = (0..length).map(|i| curve.apply((2.0 * PI * i as f64 / length as f64).cos())).collect();
+        for (k, expected) in amplitudes.iter().enumerate() {
+            let harmonic = 2.0 / length as f64 * output.iter().enumerate()
+                .map(|(i, y)| y * (2.0 * PI * (k + 1) as f64 * i as f64 / length as f64).cos())
+                .sum::<f64>();
+            assert!((harmonic - expected).abs() < 1e-9, "harmonic {} was {}", k + 1, harmonic);
+        }
+        assert_eq!(curve.apply(0.0), 0.0);
+    }
+
+    #[test]
+    fn table_and_spline_pass_through_their_points() {
+        let table = TransferCurve::table(vec![-1.0, -0.2, 0.0, 0.5, 0.8]);
+        assert!((table.apply(-0.5) + 0.2).abs() < 1e-12);
+        assert!((table.apply(0.75) - 0.65).abs() < 1e-12);
+        assert_eq!(table.apply(3.0), 0.8);
+
+        let points = vec![(-1.0, -0.8), (-0.3, -0.4), (0.0, 0.0), (0.4, 0.7), (1.0, 0.9)];
+        let spline = TransferCurve::spline(points.clone());
+        for (x, y) in points {
+            assert!((spline.apply(x) - y).abs() < 1e-12);
+        }
+        // monotone data gives a monotone curve
+        let values: Vec<f64> = (0..=200).map(|i| spline.apply(i as f64 / 100.0 - 1.0)).collect();
+        assert!(values.windows(2).all(|pair| pair[1] >= pair[0]));
+    }
+}
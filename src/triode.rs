@@ -0,0 +1,279 @@
+use std::f64::consts::PI;
+
+use fundsp::{DEFAULT_SR, audionode::AudioNode, prelude::An};
+use numeric_array::typenum::{self};
+
+use crate::{filter::DcBlocker, oversampling::{DEFAULT_OVERSAMPLING, Oversampler}};
+
+// koren's 12AX7 constants, plate current in amps
+const MU: f64 = 100.0;
+const EX: f64 = 1.4;
+const KG1: f64 = 1060.0;
+const KP: f64 = 600.0;
+const KVB: f64 = 300.0;
+
+// what drives the grid through the coupling cap, like the plate resistor of a previous stage
+const SOURCE_RESISTANCE: f64 = 68_000.0;
+// grid to cathode resistance once the grid goes positive
+const GRID_CONDUCTION_RESISTANCE: f64 = 1_000.0;
+const SOLVER_ITERATIONS: usize = 30;
+
+// a common cathode gain stage, the defaults are a typical 12AX7 preamp stage
+#[derive(Clone, Copy)]
+pub struct TriodeSettings {
+    // supply voltage at the top of the plate resistor
+    pub plate_voltage: f64,
+    pub plate_resistor: f64,
+    pub cathode_resistor: f64,
+    // 0.0 leaves the cathode unbypassed, lower gain and the cathode no longer sags with the
+    // level. grid conduction still shifts the bias through the coupling cap
+    pub cathode_bypass_uf: f64,
+    // the input coupling cap, charged by grid current on loud peaks
+    pub coupling_cap_nf: f64,
+    // grid leak, discharges the coupling cap
+    pub grid_resistor: f64,
+}
+
+impl Default for TriodeSettings {
+    fn default() -> Self {
+        Self {
+            plate_voltage: 250.0,
+            plate_resistor: 100_000.0,
+            cathode_resistor: 1_500.0,
+            cathode_bypass_uf: 22.0,
+            coupling_cap_nf: 22.0,
+            grid_resistor: 1_000_000.0,
+        }
+    }
+}
+
+// koren's plate current for a grid to cathode and a plate to cathode voltage
+pub fn plate_current(grid_voltage: f64, plate_voltage: f64) -> f64 {
+    if plate_voltage <= 0.0 {
+        return 0.0;
+    }
+    let z = KP * (1.0 / MU + grid_voltage / (KVB + plate_voltage * plate_voltage).sqrt());
+    // ln(1 + e^z) without overflowing
+    let softplus = if z > 30.0 { z } else { z.exp().ln_1p() };
+    let e1 = plate_voltage / KP * softplus;
+    2.0 * e1.powf(EX) / KG1
+}
+
+// a triode stage whose operating point moves with the signal. loud peaks pull the grid
+// positive, the grid conducts and charges the coupling cap, which biases the stage colder
+// until the grid leak discharges it again (blocking). the cathode cap follows the average
+// plate current, so the bias also sags with the level. the output is the plate swing,
+// flipped back and scaled to unity small signal gain, so drive only changes the character
+#[derive(Clone)]
+pub struct Triode {
+    settings: TriodeSettings,
+    // volts at the grid for a full scale input
+    drive: f64,
+    sample_rate: f64,
+    // cathode cap decay per sample, 0.0 when unbypassed
+    cathode_decay: f64,
+    // quiescent cathode voltage, plate voltage and current
+    cathode_bias: f64,
+    plate_bias: f64,
+    current_bias: f64,
+    // plate volts per grid volt around the operating point
+    gain: f64,
+    coupling_voltage: f64,
+    cathode_voltage: f64,
+    current: f64,
+    output_coupling: DcBlocker,
+}
+
+impl Triode {
+    pub fn new(sample_rate: f64, settings: TriodeSettings, drive: f64) -> Self {
+        let settings = TriodeSettings {
+            plate_voltage: settings.plate_voltage.max(1.0),
+            plate_resistor: settings.plate_resistor.max(1.0),
+            cathode_resistor: settings.cathode_resistor.max(1.0),
+            cathode_bypass_uf: settings.cathode_bypass_uf.max(0.0),
+            coupling_cap_nf: settings.coupling_cap_nf.max(1.0),
+            grid_resistor: settings.grid_resistor.max(1.0),
+        };
+
+        let mut triode = Self {
+            settings,
+            drive: drive.max(0.0),
+            sample_rate,
+            cathode_decay: 0.0,
+            cathode_bias: 0.0,
+            plate_bias: settings.plate_voltage,
+            current_bias: 0.0,
+            gain: 1.0,
+            coupling_voltage: 0.0,
+            cathode_voltage: 0.0,
+            current: 0.0,
+            output_coupling: DcBlocker::new(sample_rate, 0.0),
+        };
+
+        // dc operating point, the cathode cap is fully charged
+        triode.current_bias = triode.solve(0.0, 0.0, 0.0);
+        triode.cathode_bias = settings.cathode_resistor * triode.current_bias;
+        triode.plate_bias = settings.plate_voltage - settings.plate_resistor * triode.current_bias;
+
+        triode.set_sample_rate(sample_rate);
+        triode.reset();
+        triode
+    }
+
+    // plate current for a grid voltage, where the cathode moves from cathode_voltage towards
+    // cathode_resistor * current by 1 - decay. the residual only grows with the current,
+    // so newton's method is kept inside a bracket and falls back to bisection
+    fn solve(&self, grid_voltage: f64, cathode_voltage: f64, decay: f64) -> f64 {
+        let s = &self.settings;
+        let residual = |i: f64| {
+            let cathode = decay * cathode_voltage + (1.0 - decay) * s.cathode_resistor * i;
+            let plate = s.plate_voltage - s.plate_resistor * i;
+            i - plate_current(grid_voltage - cathode, plate - cathode)
+        };
+
+        let (mut low, mut high) = (0.0, s.plate_voltage / s.plate_resistor);
+        let mut i = self.current.clamp(low, high);
+        for _ in 0..SOLVER_ITERATIONS {
+            let r = residual(i);
+            if r > 0.0 {
+                high = i;
+            } else {
+                low = i;
+            }
+            let h = 1e-9 * high.max(1e-6);
+            let slope = (residual(i + h) - r) / h;
+            let mut next = i - r / slope;
+            if !(next > low && next < high) {
+                next = 0.5 * (low + high);
+            }
+            if (next - i).abs() < 1e-12 {
+                return next;
+            }
+            i = next;
+        }
+        i
+    }
+
+    pub fn process_sample(&mut self, x: f64) -> f64 {
+        let s = self.settings;
+        let dt = 1.0 / self.sample_rate;
+
+        // the grid node after the coupling cap, clamped near the cathode while it conducts
+        let source = x * self.drive - self.coupling_voltage;
+        let mut grid = source * s.grid_resistor / (SOURCE_RESISTANCE + s.grid_resistor);
+        if grid > self.cathode_voltage {
+            grid = (source / SOURCE_RESISTANCE + self.cathode_voltage / GRID_CONDUCTION_RESISTANCE)
+                / (1.0 / SOURCE_RESISTANCE + 1.0 / s.grid_resistor + 1.0 / GRID_CONDUCTION_RESISTANCE);
+        }
+        self.coupling_voltage += dt * (source - grid) / SOURCE_RESISTANCE / (s.coupling_cap_nf * 1e-9);
+
+        self.current = self.solve(grid, self.cathode_voltage, self.cathode_decay);
+        self.cathode_voltage = self.cathode_decay * self.cathode_voltage
+            + (1.0 - self.cathode_decay) * s.cathode_resistor * self.current;
+        let plate = s.plate_voltage - s.plate_resistor * self.current;
+
+        self.output_coupling.process_sample((self.plate_bias - plate) / (self.gain * self.drive.max(1e-9)))
+    }
+
+    // plate volts per input volt around the operating point, with the cathode moving
+    // as much as it does in one sample, so an unbypassed cathode lowers the gain
+    fn small_signal_gain(&self) -> f64 {
+        let s = &self.settings;
+        let dv = 1e-3;
+        let up = self.solve(dv, self.cathode_bias, self.cathode_decay);
+        let down = self.solve(-dv, self.cathode_bias, self.cathode_decay);
+        let divider = s.grid_resistor / (SOURCE_RESISTANCE + s.grid_resistor);
+        (divider * s.plate_resistor * (up - down) / (2.0 * dv)).max(1e-9)
+    }
+
+    // how far the grid sits below its quiescent bias, in volts
+    pub fn bias_shift(&self) -> f64 {
+        self.coupling_voltage + self.cathode_voltage - self.cathode_bias
+    }
+}
+
+impl AudioNode for Triode {
+    const ID: u64 = 9977;
+    type Sample = f64;
+    type Inputs = typenum::U1;
+    type Outputs = typenum::U1;
+    type Setting = f64;
+
+    fn reset(&mut self) {
+        self.coupling_voltage = 0.0;
+        self.cathode_voltage = self.cathode_bias;
+        self.current = self.current_bias;
+        self.output_coupling.reset();
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        let s = self.settings;
+        self.sample_rate = sample_rate;
+        let tau = s.cathode_resistor * s.cathode_bypass_uf * 1e-6;
+        self.cathode_decay = if tau > 0.0 { (-1.0 / (tau * sample_rate)).exp() } else { 0.0 };
+        self.gain = self.small_signal_gain();
+        // the output coupling cap into the next grid leak
+        let cutoff = 1.0 / (2.0 * PI * s.grid_resistor * s.coupling_cap_nf * 1e-9);
+        self.output_coupling = DcBlocker::new(sample_rate, cutoff);
+    }
+
+    fn tick(
+        &mut self,
+        input: &fundsp::prelude::Frame<Self::Sample, Self::Inputs>,
+    ) -> fundsp::prelude::Frame<Self::Sample, Self::Outputs> {
+        let y = self.process_sample(input[0]);
+        [y].into()
+    }
+}
+
+/// created a new triode stage where:
+///- drive is the peak grid voltage for a full scale input, around 1.0 is clean and 20.0 blocks hard
+///- settings are the component values, TriodeSettings::default() is a 12AX7 preamp stage
+///- it runs oversampled by DEFAULT_OVERSAMPLING
+pub fn my_triode(sample_rate: f64, settings: TriodeSettings, drive: f64) -> An<Oversampler<Triode>> {
+    let mut node = Oversampler::new(DEFAULT_OVERSAMPLING, Triode::new(DEFAULT_SR, settings, drive));
+    node.set_sample_rate(sample_rate);
+    An(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // level of the 1kHz component, so the output coupling settling after a step doesn't count
+    fn amplitude(triode: &mut Triode, level: f64, length: usize) -> f64 {
+        let (re, im) = (0..length).fold((0.0, 0.0), |(re, im), i| {
+            let phase = 2.0 * PI * 1000.0 * i as f64 / 44100.0;
+            let y = triode.process_sample(level * phase.sin());
+            (re + y * phase.cos(), im + y * phase.sin())
+        });
+        2.0 * (re * re + im * im).sqrt() / length as f64
+    }
+
+    #[test]
+    fn small_signals_pass_at_unity_gain() {
+        let unbypassed = TriodeSettings { cathode_bypass_uf: 0.0, ..TriodeSettings::default() };
+        for settings in [TriodeSettings::default(), unbypassed] {
+            let mut triode = Triode::new(44100.0, settings, 1.0);
+            amplitude(&mut triode, 0.01, 44100);
+            let level = amplitude(&mut triode, 0.01, 4410);
+            assert!((level - 0.01).abs() < 0.0005, "level was {}", level);
+        }
+    }
+
+    #[test]
+    fn loud_bursts_shift_the_bias_and_recover() {
+        let mut triode = Triode::new(44100.0, TriodeSettings::default(), 1.0);
+        amplitude(&mut triode, 0.2, 44100);
+        let before = amplitude(&mut triode, 0.2, 441);
+
+        amplitude(&mut triode, 40.0, 4410);
+        assert!(triode.bias_shift() > 1.0, "shift was {}", triode.bias_shift());
+        let blocked = amplitude(&mut triode, 0.2, 441);
+        assert!(blocked < 0.5 * before, "{} against {}", blocked, before);
+
+        amplitude(&mut triode, 0.2, 3 * 44100);
+        let recovered = amplitude(&mut triode, 0.2, 441);
+        assert!((recovered - before).abs() < 0.05 * before, "{} against {}", recovered, before);
+    }
+}
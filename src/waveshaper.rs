@@ -4,6 +4,10 @@ use circular_buffer::CircularBuffer;
 use fundsp::{audionode::AudioNode, prelude::An, shape::Shape};
 use numeric_array::typenum::{*, self};
 
+use fundsp::DEFAULT_SR;
+
+use crate::{filter::DcBlocker, oversampling::{DEFAULT_OVERSAMPLING, Oversampler}};
+
 #[derive(Clone)]
 pub enum ShapeType {
     ARRY,
@@ -107,12 +111,169 @@ pub fn asqrt(x: f64) -> f64 {
     sgn(x) * x.abs().sqrt()
 }
 
+// ln(cosh(x)) without overflowing for large x
+fn log_cosh(x: f64) -> f64 {
+    let x = x.abs();
+    x + (-2.0 * x).exp().ln_1p() - std::f64::consts::LN_2
+}
+
+// gauss-legendre nodes and weights on [-1, 1]
+const GAUSS_NODES: [(f64, f64); 5] = [
+    (0.0, 0.5688888888888889),
+    (-0.5384693101056831, 0.4786286704993665),
+    (0.5384693101056831, 0.4786286704993665),
+    (-0.906179845938664, 0.23692688538714),
+    (0.906179845938664, 0.23692688538714),
+];
+
+// mean of f over [a, b], exact for smooth functions up to the 9th degree on each panel.
+// unlike a difference of antiderivatives it stays accurate when a and b are close
+fn mean_value(f: impl Fn(f64) -> f64, a: f64, b: f64) -> f64 {
+    let panels = ((b - a).abs() / 0.25).ceil().clamp(1.0, 64.0) as usize;
+    let width = (b - a) / panels as f64;
+    let mut sum = 0.0;
+    for panel in 0..panels {
+        let center = a + (panel as f64 + 0.5) * width;
+        for (node, weight) in GAUSS_NODES.iter() {
+            sum += weight * f(center + 0.5 * width * node);
+        }
+    }
+    sum / (2.0 * panels as f64)
+}
+
+impl ShapeType {
+    pub fn apply(&self, x: f64, saturation: f64) -> f64 {
+        match self {
+            ShapeType::ARRY => arry(x),
+            ShapeType::SIG => sig(x, saturation),
+            ShapeType::SIG2 => sig2(x),
+            ShapeType::TANH => tanh(x, saturation),
+            ShapeType::ATAN => atan(x, saturation),
+            ShapeType::FEXP1 => fexp1(x, saturation),
+            ShapeType::FEXP2 => fexp2(x),
+            ShapeType::EXP => exp(x),
+            ShapeType::ATSR => atsr(x),
+            ShapeType::SQS => sqs(x),
+            ShapeType::CUBE => cube(x),
+            ShapeType::HCLIP => hclip(x),
+            ShapeType::HWR => hwr(x),
+            ShapeType::FWR => fwr(x),
+            ShapeType::ASQRT => asqrt(x),
+        }
+    }
+
+    // first antiderivative, every shape has a closed form
+    pub fn antiderivative(&self, x: f64, saturation: f64) -> f64 {
+        let s = saturation;
+        match self {
+            ShapeType::ARRY => 0.75 * x * x - 0.125 * x.powi(4),
+            // sig is tanh(s * x / 2)
+            ShapeType::SIG => if s > 0.0 { 2.0 / s * log_cosh(0.5 * s * x) } else { 0.0 },
+            ShapeType::SIG2 => 2.0 * (E + 1.0) / (E - 1.0) * log_cosh(0.5 * x),
+            ShapeType::TANH => log_cosh(s * x) / (s * s.tanh()),
+            ShapeType::ATAN => (x * (s * x).atan() - (s * s * x * x).ln_1p() / (2.0 * s)) / s.atan(),
+            ShapeType::FEXP1 => (x.abs() + ((-s * x.abs()).exp() - 1.0) / s) / (1.0 - (-s).exp()),
+            ShapeType::FEXP2 => (x.abs() - x.abs().exp() + 1.0) / (E - 1.0),
+            ShapeType::EXP => (E * x + (1.0 - x).exp()) / (E - 1.0),
+            ShapeType::ATSR => {
+                let u = 0.9 * x;
+                2.5 * (x * u.atan() - (u * u).ln_1p() / 1.8)
+                + 2.5 / 0.9 * 0.5 * (u * (1.0 - u * u).sqrt() + u.asin())
+                - 2.5 * x
+            },
+            ShapeType::SQS => x.abs().powi(3) / 3.0,
+            ShapeType::CUBE => x.powi(4) / 4.0,
+            ShapeType::HCLIP => if x.abs() <= 0.5 { 0.5 * x * x } else { 0.5 * x.abs() - 0.125 },
+            ShapeType::HWR => 0.5 * x.max(0.0).powi(2),
+            ShapeType::FWR => 0.5 * x * x.abs(),
+            ShapeType::ASQRT => 2.0 / 3.0 * x.abs().powf(1.5),
+        }
+    }
+
+    // second antiderivative, None where there is no closed form (it needs a polylogarithm)
+    pub fn second_antiderivative(&self, x: f64, saturation: f64) -> Option<f64> {
+        let s = saturation;
+        let y = match self {
+            ShapeType::ARRY => 0.25 * x.powi(3) - 0.025 * x.powi(5),
+            ShapeType::ATAN => {
+                ((0.5 * x * x - 0.5 / (s * s)) * (s * x).atan()
+                + x / (2.0 * s)
+                - x * (s * s * x * x).ln_1p() / (2.0 * s)) / s.atan()
+            },
+            ShapeType::FEXP1 => {
+                sgn(x) * (0.5 * x * x - x.abs() / s + (1.0 - (-s * x.abs()).exp()) / (s * s)) / (1.0 - (-s).exp())
+            },
+            ShapeType::FEXP2 => sgn(x) * (0.5 * x * x + x.abs() - x.abs().exp() + 1.0) / (E - 1.0),
+            ShapeType::EXP => (0.5 * E * x * x - (1.0 - x).exp()) / (E - 1.0),
+            ShapeType::SQS => sgn(x) * x.powi(4) / 12.0,
+            ShapeType::CUBE => x.powi(5) / 20.0,
+            ShapeType::HCLIP => {
+                if x.abs() <= 0.5 {
+                    x.powi(3) / 6.0
+                } else {
+                    sgn(x) * (0.25 * x * x - 0.125 * x.abs() + 1.0 / 48.0)
+                }
+            },
+            ShapeType::HWR => x.max(0.0).powi(3) / 6.0,
+            ShapeType::FWR => x.abs().powi(3) / 6.0,
+            ShapeType::ASQRT => sgn(x) * 4.0 / 15.0 * x.abs().powf(2.5),
+            ShapeType::SIG | ShapeType::SIG2 | ShapeType::TANH | ShapeType::ATSR => return None,
+        };
+        Some(y)
+    }
+
+    // mean of the shape over [a, b], the first order adaa output
+    fn mean(&self, a: f64, b: f64, saturation: f64) -> f64 {
+        if (b - a).abs() > ADAA_TOLERANCE {
+            (self.antiderivative(b, saturation) - self.antiderivative(a, saturation)) / (b - a)
+        } else {
+            self.apply(0.5 * (a + b), saturation)
+        }
+    }
+
+    // mean of the first antiderivative over [a, b], integrated numerically without a closed form
+    fn mean_antiderivative(&self, a: f64, b: f64, saturation: f64) -> f64 {
+        let closed_form = self.second_antiderivative(b, saturation)
+            .zip(self.second_antiderivative(a, saturation));
+        match closed_form {
+            Some((fb, fa)) if (b - a).abs() > ADAA_TOLERANCE => (fb - fa) / (b - a),
+            Some(_) => self.antiderivative(0.5 * (a + b), saturation),
+            None => mean_value(|x| self.antiderivative(x, saturation), a, b),
+        }
+    }
+}
+
+// high-pass after a biased shaper, low enough to keep the bass
+pub const DEFAULT_DC_BLOCK_CUTOFF: f64 = 10.0;
+
+// below this the antiderivative differences are ill-conditioned and we fall back to the midpoint
+const ADAA_TOLERANCE: f64 = 1e-5;
+
+// antiderivative antialiasing, a cheaper alternative to oversampling. the first order
+// adds half a sample of delay and the second order a whole sample
+#[derive(Clone, Copy, PartialEq)]
+pub enum Antialiasing {
+    NONE,
+    ADAA1,
+    ADAA2,
+}
+
 #[derive(Clone)]
 pub struct Waveshaper {
     shape_type: ShapeType,
     saturation: f64,
     pre_gain: f64,
     post_gain: f64,
+    antialiasing: Antialiasing,
+    // added after the pre gain, moves the operating point off the center of the curve
+    bias: f64,
+    // output of the curve at the bias, taken off so silence stays silent
+    offset: f64,
+    dc_blocker: Option<DcBlocker>,
+    sample_rate: f64,
+    // the last two inputs after the pre gain and bias
+    x1: f64,
+    x2: f64,
 }
 
 impl Waveshaper {
@@ -131,34 +292,82 @@ impl Waveshaper {
             saturation,
             pre_gain,
             post_gain,
+            antialiasing: Antialiasing::NONE,
+            bias: 0.0,
+            offset: 0.0,
+            dc_blocker: None,
+            sample_rate: DEFAULT_SR,
+            x1: 0.0,
+            x2: 0.0,
+        }
+    }
+
+    pub fn with_antialiasing(mut self, antialiasing: Antialiasing) -> Self {
+        self.antialiasing = antialiasing;
+        self
+    }
+
+    // a biased curve clips one half of the wave before the other, which makes even
+    // harmonics and a signal dependent dc offset. turns on the dc blocker if it is off
+    pub fn with_bias(mut self, bias: f64) -> Self {
+        self.bias = bias;
+        self.offset = self.shape_type.apply(bias, self.saturation);
+        self.x1 = bias;
+        self.x2 = bias;
+        if self.dc_blocker.is_none() {
+            self = self.with_dc_blocker(Some(DEFAULT_DC_BLOCK_CUTOFF));
         }
+        self
+    }
+
+    // cutoff in Hz of the high-pass after the curve, None turns it off
+    pub fn with_dc_blocker(mut self, cutoff: Option<f64>) -> Self {
+        self.dc_blocker = cutoff.map(|cutoff| DcBlocker::new(self.sample_rate, cutoff));
+        self
     }
 
     pub fn process_sample(&mut self, x: f64) -> f64 {
         // initial gain and had clipping at |x|=1.0
 
-        let x = x * self.pre_gain;
+        let x = x * self.pre_gain + self.bias;
 
-        let y = match self.shape_type {
-            ShapeType::ARRY => arry(x),
-            ShapeType::SIG => sig(x, self.saturation),
-            ShapeType::SIG2 => sig2(x),
-            ShapeType::TANH => tanh(x, self.saturation),
-            ShapeType::ATAN => atan(x, self.saturation),
-            ShapeType::FEXP1 => fexp1(x, self.saturation),
-            ShapeType::FEXP2 => fexp2(x),
-            ShapeType::EXP => exp(x),
-            ShapeType::ATSR => atsr(x),
-            ShapeType::SQS => sqs(x),
-            ShapeType::CUBE => cube(x),
-            ShapeType::HCLIP => hclip(x),
-            ShapeType::HWR => hwr(x),
-            ShapeType::FWR => fwr(x),
-            ShapeType::ASQRT => asqrt(x),
+        let y = match self.antialiasing {
+            Antialiasing::NONE => self.shape_type.apply(x, self.saturation),
+            Antialiasing::ADAA1 => self.shape_type.mean(self.x1, x, self.saturation),
+            Antialiasing::ADAA2 => self.adaa2(x),
         };
+        self.x2 = self.x1;
+        self.x1 = x;
+
+        let mut y = y - self.offset;
+        if let Some(blocker) = self.dc_blocker.as_mut() {
+            y = blocker.process_sample(y);
+        }
 
         y * self.post_gain
     }
+
+    // second order adaa:
+    // y(n) = 2 / (x0 - x2) * (D(x0, x1) - D(x1, x2))
+    // where D(a, b) is the mean of the first antiderivative between a and b
+    fn adaa2(&self, x0: f64) -> f64 {
+        let (x1, x2) = (self.x1, self.x2);
+        let shape = &self.shape_type;
+        let s = self.saturation;
+
+        if (x0 - x2).abs() > ADAA_TOLERANCE {
+            return 2.0 / (x0 - x2) * (shape.mean_antiderivative(x1, x0, s) - shape.mean_antiderivative(x2, x1, s));
+        }
+
+        // x0 and x2 are (almost) the same, expand around their midpoint instead
+        let mid = 0.5 * (x0 + x2);
+        let delta = mid - x1;
+        if delta.abs() > ADAA_TOLERANCE {
+            2.0 / delta * (shape.antiderivative(mid, s) - shape.mean_antiderivative(x1, mid, s))
+        } else {
+            shape.apply(0.5 * (mid + x1), s)
+        }
+    }
 }
 
 impl AudioNode for Waveshaper {
@@ -168,7 +377,20 @@ impl AudioNode for Waveshaper {
     type Outputs = typenum::U1;
     type Setting = f64;
 
-    fn reset(&mut self) {}
+    fn reset(&mut self) {
+        self.x1 = self.bias;
+        self.x2 = self.bias;
+        if let Some(blocker) = self.dc_blocker.as_mut() {
+            blocker.reset();
+        }
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        if let Some(blocker) = self.dc_blocker.as_mut() {
+            blocker.set_sample_rate(sample_rate);
+        }
+    }
 
     fn tick(
             &mut self,
@@ -181,59 +403,102 @@ impl AudioNode for Waveshaper {
     }
 }
 
-pub fn my_waveshaper(shape_type: ShapeType, pre_gain: f64, post_gain: f64, saturation: Option<f64> ) -> An<Waveshaper> {
-    if saturation.is_some() {
-        return An(Waveshaper::new(shape_type, saturation.unwrap(), pre_gain, post_gain));
-    }
-    An(Waveshaper::new(shape_type, 1.0, pre_gain, post_gain))
+// oversampled by DEFAULT_OVERSAMPLING, see my_waveshaper_oversampled
+pub fn my_waveshaper(shape_type: ShapeType, pre_gain: f64, post_gain: f64, saturation: Option<f64> ) -> An<Oversampler<Waveshaper>> {
+    my_waveshaper_oversampled(shape_type, pre_gain, post_gain, saturation, DEFAULT_OVERSAMPLING)
+}
+
+// oversampling is 1, 2, 4, 8 or 16, 1 runs the shaper at the base rate
+pub fn my_waveshaper_oversampled(shape_type: ShapeType, pre_gain: f64, post_gain: f64, saturation: Option<f64>, oversampling: usize) -> An<Oversampler<Waveshaper>> {
+    An(Oversampler::new(oversampling, Waveshaper::new(shape_type, saturation.unwrap_or(1.0), pre_gain, post_gain)))
 }
 
+// one curve for the positive half of the wave and another for the negative half,
+// each with its own gain and saturation. the halves meet at zero after the pre gain
+// and the bias, so a bias moves the switching point across the input
 #[derive(Clone)]
 pub struct AssymetricWaveshaper {
-    up_shaper: Waveshaper,
-    down_shaper: Waveshaper,
-    saturation: f64,
+    up_shape: ShapeType,
+    down_shape: ShapeType,
+    up_gain: f64,
+    up_saturation: f64,
+    down_gain: f64,
+    down_saturation: f64,
     pre_gain: f64,
     post_gain: f64,
+    bias: f64,
+    offset: f64,
+    dc_blocker: Option<DcBlocker>,
+    sample_rate: f64,
 }
 
 impl AssymetricWaveshaper {
     pub fn new(up_shape: ShapeType, down_shape: ShapeType, saturation: f64, pre_gain: f64, post_gain: f64) -> Self {
-        let saturation = if saturation < 0.0 {
-            0.0
-        } else {
-            saturation
-        };
+        let saturation = saturation.max(0.0);
 
         let pre_gain = pre_gain.max(0.0);
         let post_gain = post_gain.max(0.0);
 
         Self {
-            up_shaper: Waveshaper::new(up_shape, saturation, pre_gain, post_gain),
-            down_shaper: Waveshaper::new(down_shape, saturation, pre_gain, post_gain),
-            saturation,
+            up_shape,
+            down_shape,
+            up_gain: 1.0,
+            up_saturation: saturation,
+            down_gain: 1.0,
+            down_saturation: saturation,
             pre_gain,
             post_gain,
+            bias: 0.0,
+            offset: 0.0,
+            dc_blocker: None,
+            sample_rate: DEFAULT_SR,
         }
     }
 
-    pub fn process_sample(&mut self, x: f64) -> f64 {
-        // initial gain and had clipping at |x|=1.0
+    // gain and saturation of the positive half
+    pub fn with_up(mut self, gain: f64, saturation: f64) -> Self {
+        self.up_gain = gain.max(0.0);
+        self.up_saturation = saturation.max(0.0);
+        self.offset = self.shape(self.bias);
+        self
+    }
 
-        let x = x * self.pre_gain;
+    // gain and saturation of the negative half
+    pub fn with_down(mut self, gain: f64, saturation: f64) -> Self {
+        self.down_gain = gain.max(0.0);
+        self.down_saturation = saturation.max(0.0);
+        self.offset = self.shape(self.bias);
+        self
+    }
 
-        self.up_shaper.pre_gain = self.pre_gain;
-        self.up_shaper.post_gain = self.post_gain;
-        self.up_shaper.saturation = self.saturation;
-        self.down_shaper.pre_gain = self.pre_gain; 
-        self.down_shaper.post_gain = self.post_gain;
-        self.down_shaper.saturation = self.saturation;
+    // same as Waveshaper::with_bias
+    pub fn with_bias(mut self, bias: f64) -> Self {
+        self.bias = bias;
+        self.offset = self.shape(bias);
+        if self.dc_blocker.is_none() {
+            self = self.with_dc_blocker(Some(DEFAULT_DC_BLOCK_CUTOFF));
+        }
+        self
+    }
+
+    pub fn with_dc_blocker(mut self, cutoff: Option<f64>) -> Self {
+        self.dc_blocker = cutoff.map(|cutoff| DcBlocker::new(self.sample_rate, cutoff));
+        self
+    }
 
-        let y = if x >= 0.0 {
-            self.up_shaper.process_sample(x)
+    fn shape(&self, x: f64) -> f64 {
+        if x >= 0.0 {
+            self.up_shape.apply(x * self.up_gain, self.up_saturation)
         } else {
-            self.down_shaper.process_sample(x)
-        };
+            self.down_shape.apply(x * self.down_gain, self.down_saturation)
+        }
+    }
+
+    pub fn process_sample(&mut self, x: f64) -> f64 {
+        let mut y = self.shape(x * self.pre_gain + self.bias) - self.offset;
+        if let Some(blocker) = self.dc_blocker.as_mut() {
+            y = blocker.process_sample(y);
+        }
 
         y * self.post_gain
     }
@@ -246,7 +511,18 @@ impl AudioNode for AssymetricWaveshaper {
     type Outputs = typenum::U1;
     type Setting = f64;
 
-    fn reset(&mut self) {}
+    fn reset(&mut self) {
+        if let Some(blocker) = self.dc_blocker.as_mut() {
+            blocker.reset();
+        }
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        if let Some(blocker) = self.dc_blocker.as_mut() {
+            blocker.set_sample_rate(sample_rate);
+        }
+    }
 
     fn tick(
             &mut self,
@@ -259,9 +535,149 @@ impl AudioNode for AssymetricWaveshaper {
     }
 }
 
-pub fn my_assymetric_waveshaper(up_shape: ShapeType, down_shape: ShapeType, pre_gain: f64, post_gain: f64, saturation: Option<f64> ) -> An<AssymetricWaveshaper> {
-    if saturation.is_some() {
-        return An(AssymetricWaveshaper::new(up_shape, down_shape, saturation.unwrap(), pre_gain, post_gain));
+// antialiased with adaa instead of oversampling
+pub fn my_adaa_waveshaper(shape_type: ShapeType, pre_gain: f64, post_gain: f64, saturation: Option<f64>, antialiasing: Antialiasing) -> An<Waveshaper> {
+    An(Waveshaper::new(shape_type, saturation.unwrap_or(1.0), pre_gain, post_gain).with_antialiasing(antialiasing))
+}
+
+// oversampled by DEFAULT_OVERSAMPLING
+pub fn my_assymetric_waveshaper(up_shape: ShapeType, down_shape: ShapeType, pre_gain: f64, post_gain: f64, saturation: Option<f64> ) -> An<Oversampler<AssymetricWaveshaper>> {
+    An(Oversampler::new(DEFAULT_OVERSAMPLING, AssymetricWaveshaper::new(up_shape, down_shape, saturation.unwrap_or(1.0), pre_gain, post_gain)))
+}
+
+/// created a new biased waveshaper where:
+///- bias is added after the pre gain, 0.0 is the plain curve
+///- the output goes through a DEFAULT_DC_BLOCK_CUTOFF high-pass before the post gain
+///- it runs oversampled by DEFAULT_OVERSAMPLING
+pub fn my_biased_waveshaper(sample_rate: f64, shape_type: ShapeType, pre_gain: f64, post_gain: f64, saturation: Option<f64>, bias: f64) -> An<Oversampler<Waveshaper>> {
+    let shaper = Waveshaper::new(shape_type, saturation.unwrap_or(1.0), pre_gain, post_gain).with_bias(bias);
+    // the oversampler hands the dc blocker the oversampled rate
+    let mut node = Oversampler::new(DEFAULT_OVERSAMPLING, shaper);
+    node.set_sample_rate(sample_rate);
+    An(node)
+}
+
+/// created a new biased asymmetric waveshaper where:
+///- gain is (pre gain, post gain)
+///- up and down are (gain, saturation) of the positive and negative half
+///- bias is added after the pre gain and moves the point where the halves switch
+///- the output is dc blocked and the shaper runs oversampled by DEFAULT_OVERSAMPLING
+pub fn my_biased_assymetric_waveshaper(sample_rate: f64, up_shape: ShapeType, down_shape: ShapeType, gain: (f64, f64), up: (f64, f64), down: (f64, f64), bias: f64) -> An<Oversampler<AssymetricWaveshaper>> {
+    let shaper = AssymetricWaveshaper::new(up_shape, down_shape, 1.0, gain.0, gain.1)
+        .with_up(up.0, up.1)
+        .with_down(down.0, down.1)
+        .with_bias(bias);
+    // the oversampler hands the dc blocker the oversampled rate
+    let mut node = Oversampler::new(DEFAULT_OVERSAMPLING, shaper);
+    node.set_sample_rate(sample_rate);
+    An(node)
+}
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use rustfft::{FftPlanner, num_complex::Complex};
+
+    use super::*;
+
+    const SHAPES: [ShapeType; 15] = [
+        ShapeType::ARRY, ShapeType::SIG, ShapeType::SIG2, ShapeType::TANH, ShapeType::ATAN,
+        ShapeType::FEXP1, ShapeType::FEXP2, ShapeType::EXP, ShapeType::ATSR, ShapeType::SQS,
+        ShapeType::CUBE, ShapeType::HCLIP, ShapeType::HWR, ShapeType::FWR, ShapeType::ASQRT,
+    ];
+
+    #[test]
+    fn antiderivatives_match_their_shapes() {
+        let h = 1e-5;
+        for shape in SHAPES.iter() {
+            // inside atsr's domain and away from the kinks at 0 and +-0.5
+            for x in [-0.93, -0.61, -0.27, 0.13, 0.38, 0.74, 1.05] {
+                let derivative = (shape.antiderivative(x + h, 2.0) - shape.antiderivative(x - h, 2.0)) / (2.0 * h);
+                assert!((derivative - shape.apply(x, 2.0)).abs() < 1e-6);
+
+                if shape.second_antiderivative(x, 2.0).is_some() {
+                    let second = |x| shape.second_antiderivative(x, 2.0).unwrap();
+                    let derivative = (second(x + h) - second(x - h)) / (2.0 * h);
+                    assert!((derivative - shape.antiderivative(x, 2.0)).abs() < 1e-6);
+                }
+            }
+        }
+    }
+
+    // energy that doesn't land on a harmonic of f0 against the energy that does, in dB
+    fn aliasing_db(antialiasing: Antialiasing, shape: ShapeType, pre_gain: f64) -> f64 {
+        let sample_rate = 44100.0;
+        let f0 = 4999.0;
+        let length = 1 << 15;
+        let mut shaper = Waveshaper::new(shape, 2.0, pre_gain, 1.0).with_antialiasing(antialiasing);
+
+        let mut spectrum: Vec<Complex<f64>> = (0..length + 1024)
+            .map(|i| shaper.process_sample((2.0 * PI * f0 * i as f64 / sample_rate).sin()))
+            .skip(1024)
+            .enumerate()
+            .map(|(i, y)| Complex::new(y * (0.5 - 0.5 * (2.0 * PI * i as f64 / length as f64).cos()), 0.0))
+            .collect();
+        FftPlanner::new().plan_fft_forward(length).process(&mut spectrum);
+
+        let (mut harmonics, mut aliases) = (0.0, 0.0);
+        for (k, bin) in spectrum.iter().enumerate().take(length / 2).skip(1) {
+            let ratio = k as f64 * sample_rate / length as f64 / f0;
+            if (ratio - ratio.round()).abs() * f0 < 30.0 {
+                harmonics += bin.norm_sqr();
+            } else {
+                aliases += bin.norm_sqr();
+            }
+        }
+        10.0 * (aliases / harmonics).log10()
+    }
+
+    #[test]
+    fn adaa_reduces_aliasing() {
+        // closed form and numeric second antiderivatives
+        for shape in [ShapeType::TANH, ShapeType::HCLIP, ShapeType::ATAN, ShapeType::SQS, ShapeType::FWR, ShapeType::SIG] {
+            let none = aliasing_db(Antialiasing::NONE, shape.clone(), 4.0);
+            let first = aliasing_db(Antialiasing::ADAA1, shape.clone(), 4.0);
+            let second = aliasing_db(Antialiasing::ADAA2, shape, 4.0);
+            assert!(first < none - 5.0, "adaa1 {} against {}", first, none);
+            assert!(second < first - 5.0, "adaa2 {} against {}", second, first);
+        }
     }
-    An(AssymetricWaveshaper::new(up_shape, down_shape, 1.0, pre_gain, post_gain))
-}
\ No newline at end of file
+
+    #[test]
+    fn assymetric_gains_are_applied_once() {
+        let mut plain = Waveshaper::new(ShapeType::TANH, 2.0, 3.0, 0.5);
+        let mut assymetric = AssymetricWaveshaper::new(ShapeType::TANH, ShapeType::TANH, 2.0, 3.0, 0.5);
+        for x in [-0.9, -0.2, 0.0, 0.1, 0.6] {
+            assert!((plain.process_sample(x) - assymetric.process_sample(x)).abs() < 1e-12);
+        }
+
+        let mut halves = AssymetricWaveshaper::new(ShapeType::HCLIP, ShapeType::HCLIP, 1.0, 1.0, 1.0)
+            .with_up(1.0, 1.0)
+            .with_down(4.0, 1.0);
+        assert!((halves.process_sample(0.1) - 0.1).abs() < 1e-12);
+        assert!((halves.process_sample(-0.1) + 0.4).abs() < 1e-12);
+    }
+
+    #[test]
+    fn bias_adds_even_harmonics_without_dc() {
+        let sample_rate = 44100.0;
+        let length = 44100;
+        let mut shaper = Waveshaper::new(ShapeType::TANH, 2.0, 2.0, 1.0).with_bias(0.4);
+        shaper.set_sample_rate(sample_rate);
+        // whole periods of 100Hz after the blocker has settled
+        let output: Vec<f64> = (0..4 * length)
+            .map(|i| shaper.process_sample((2.0 * PI * 100.0 * i as f64 / sample_rate).sin()))
+            .skip(3 * length)
+            .collect();
+        let harmonic = |k: f64| {
+            let (re, im) = output.iter().enumerate().fold((0.0, 0.0), |(re, im), (i, y)| {
+                let phase = 2.0 * PI * k * 100.0 * i as f64 / sample_rate;
+                (re + y * phase.cos(), im + y * phase.sin())
+            });
+            2.0 * (re * re + im * im).sqrt() / length as f64
+        };
+        let mean = output.iter().sum::<f64>() / length as f64;
+        assert!(mean.abs() < 1e-3, "dc was {}", mean);
+        assert!(harmonic(2.0) > 0.05 * harmonic(1.0));
+    }
+}